@@ -0,0 +1,57 @@
+//! Checks `filepath::clean()` against Go's `path.Clean()` semantics.
+//!
+//! The reference below is a direct reading of the rules documented for
+//! `path.Clean()`, working on whole elements rather than bytes, so it
+//! shares no code or structure with the implementation under test.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mmv::filepath::clean;
+
+/// Go's `path.Clean()`:
+///
+/// 1. Replace multiple slashes with a single slash.
+/// 2. Eliminate each `.` path name element.
+/// 3. Eliminate each inner `..` path name element and the non-`..`
+///    element that precedes it.
+/// 4. Eliminate `..` elements that begin a rooted path.
+///
+/// The result is `.` if nothing is left.
+fn go_clean(path: &str) -> String {
+    let rooted = path.starts_with('/');
+    let mut names = Vec::<&str>::new();
+
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => match names.last() {
+                Some(&last) if last != ".." => {
+                    names.pop();
+                }
+                _ if rooted => {}
+                _ => names.push(".."),
+            },
+            _ => names.push(name),
+        }
+    }
+
+    match (rooted, names.is_empty()) {
+        (true, _) => format!("/{}", names.join("/")),
+        (false, true) => String::from("."),
+        (false, false) => names.join("/"),
+    }
+}
+
+fuzz_target!(|path: &str| {
+    // Paths cannot hold NUL, and Windows separators behave differently.
+    if path.contains('\0') || cfg!(windows) {
+        return;
+    }
+
+    let cleaned = clean(path);
+    let cleaned = cleaned.to_str().unwrap();
+
+    assert_eq!(cleaned, go_clean(path), "clean({:?})", path);
+    assert_eq!(clean(cleaned).to_str().unwrap(), cleaned, "clean(clean({:?}))", path);
+});
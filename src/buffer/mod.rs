@@ -0,0 +1,291 @@
+//! Edit buffer format shown to the user in the editor.
+//!
+//! Each source path is written on its own line prefixed by a
+//! stable id and a tab, Eg. `1\tfoo`.
+//!
+//! The id ties an edited line back to its source, so lines may be
+//! reordered. Besides editing paths, the user can
+//! - prefix a line with `!` to move the source to the trash, and
+//! - add lines without an id ending in `/` to create directories.
+//!
+//! Deleting a line is an error, so that no file is ever dropped
+//! from the plan by accident. Like a commit message in git, emptying
+//! the whole buffer aborts, and leaving it unchanged does nothing.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use super::filepath::clean;
+
+static TRASH_PREFIX: char = '!';
+
+/// Changes requested by the user in the edit buffer.
+#[derive(Debug, Default)]
+pub struct Edits {
+    pub renames: HashMap<PathBuf, PathBuf>,
+    pub trash: Vec<PathBuf>,
+    pub create: Vec<PathBuf>,
+}
+
+impl Edits {
+    /// Returns true if nothing would change, Eg. the buffer was saved
+    /// as is.
+    pub fn is_empty(&self) -> bool {
+        self.trash.is_empty()
+            && self.create.is_empty()
+            && self
+                .renames
+                .iter()
+                .all(|(src, dst)| clean(src) == clean(dst))
+    }
+}
+
+/// Returns true if the user emptied the buffer to abort.
+pub fn is_blank(contents: &str) -> bool {
+    contents.trim().is_empty()
+}
+
+/// Returns buffer contents listing `files` with their ids.
+pub fn format(files: &[&str]) -> String {
+    let mut contents = String::new();
+    for (i, path) in files.iter().enumerate() {
+        contents.push_str(&format!("{}\t{}\n", i + 1, path));
+    }
+
+    contents
+}
+
+/// Parses edited buffer contents back into changes for `files`.
+///
+/// Every problem is reported at once, with its line numbers, so that
+/// all of them can be fixed in a single pass.
+pub fn parse(contents: &str, files: &[&str]) -> Result<Edits, String> {
+    let mut edits = Edits::default();
+    let mut seen = vec![false; files.len()];
+    // Problems are sorted by the first line they concern.
+    let mut problems = Vec::<(usize, String)>::new();
+    // Line numbers of every destination, including new directories.
+    let mut destinations = BTreeMap::<PathBuf, Vec<usize>>::new();
+
+    for (n, line) in contents.lines().enumerate() {
+        let line_num = n + 1;
+        // Clear empty lines
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (trash, rest) = match line.strip_prefix(TRASH_PREFIX) {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+
+        let (id, path) = match parse_id(rest) {
+            Some(parsed) => parsed,
+            None if !trash && line.ends_with('/') => {
+                edits.create.push(PathBuf::from(line));
+                destinations.entry(clean(line)).or_default().push(line_num);
+                continue;
+            }
+            None => {
+                problems.push((
+                    line_num,
+                    format!(
+                        "Line {}: missing id. Only directories (ending in /) can be added.",
+                        line_num
+                    ),
+                ));
+                continue;
+            }
+        };
+
+        if id == 0 || id > files.len() {
+            problems.push((line_num, format!("Line {}: unknown id {}", line_num, id)));
+            continue;
+        }
+        if seen[id - 1] {
+            problems.push((
+                line_num,
+                format!("Line {}: id {} is used twice", line_num, id),
+            ));
+            continue;
+        }
+        seen[id - 1] = true;
+
+        let src = PathBuf::from(files[id - 1]);
+        if trash {
+            edits.trash.push(src);
+            continue;
+        }
+
+        // Whitespace alone is never an intended name.
+        if path.trim().is_empty() {
+            problems.push((
+                line_num,
+                format!(
+                    "Line {}: empty path. Prefix the line with {} to move it to the trash.",
+                    line_num, TRASH_PREFIX
+                ),
+            ));
+            continue;
+        }
+
+        let (cleaned_src, cleaned_dst) = (clean(&src), clean(path));
+        if cleaned_dst != cleaned_src && cleaned_dst.starts_with(&cleaned_src) {
+            problems.push((
+                line_num,
+                format!(
+                    "Line {}: cannot move {} into itself ({})",
+                    line_num,
+                    cleaned_src.display(),
+                    cleaned_dst.display()
+                ),
+            ));
+        }
+        destinations.entry(cleaned_dst).or_default().push(line_num);
+        edits.renames.insert(src, PathBuf::from(path));
+    }
+
+    for (dst, lines) in destinations {
+        if lines.len() > 1 {
+            let line_nums = lines
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+            problems.push((
+                lines[0],
+                format!(
+                    "Lines {}: duplicate destination {}",
+                    line_nums,
+                    dst.display()
+                ),
+            ));
+        }
+    }
+    problems.sort();
+
+    let mut errors = problems
+        .into_iter()
+        .map(|(_, problem)| problem)
+        .collect::<Vec<String>>();
+
+    let missing = files
+        .iter()
+        .zip(seen)
+        .filter(|(_, seen)| !seen)
+        .map(|(file, _)| *file)
+        .collect::<Vec<&str>>();
+    if !missing.is_empty() {
+        errors.push(format!(
+            "Do not delete lines ({} missing). Prefix a line with {} to move it to the trash.",
+            missing.join(", "),
+            TRASH_PREFIX
+        ));
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
+    Ok(edits)
+}
+
+/// Splits `<id>\t<path>` into its parts.
+fn parse_id(line: &str) -> Option<(usize, &str)> {
+    let (id, path) = line.split_once('\t')?;
+    let id = id.trim().parse::<usize>().ok()?;
+    Some((id, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format, is_blank, parse};
+    use std::path::PathBuf;
+
+    static FILES: [&str; 3] = ["foo", "bar", "baz"];
+
+    #[test]
+    fn unchanged_buffer() {
+        let edits = parse(&format(&FILES), &FILES).unwrap();
+        assert_eq!(edits.renames.len(), 3);
+        assert!(edits.renames.iter().all(|(src, dst)| src == dst));
+        assert!(edits.is_empty());
+
+        // Reordered, or spelled differently, still changes nothing.
+        assert!(parse("3\tbaz\n1\t./foo\n2\tbar/\n", &FILES)
+            .unwrap()
+            .is_empty());
+        assert!(!parse("1\tfoo\n2\tbar\n3\tqux\n", &FILES)
+            .unwrap()
+            .is_empty());
+        assert!(!parse("1\tfoo\n2\tbar\n!3\tbaz\n", &FILES)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn blank_buffer() {
+        assert!(is_blank(""));
+        assert!(is_blank("\n \t\n"));
+        assert!(!is_blank(&format(&FILES)));
+    }
+
+    #[test]
+    fn reorder_trash_and_create() {
+        let edits = parse("3\tqux\n\n!1\tfoo\nnew/dir/\n2\tbar\n", &FILES).unwrap();
+        assert_eq!(
+            edits.renames.get(&PathBuf::from("baz")),
+            Some(&PathBuf::from("qux"))
+        );
+        assert_eq!(
+            edits.renames.get(&PathBuf::from("bar")),
+            Some(&PathBuf::from("bar"))
+        );
+        assert_eq!(edits.trash, vec![PathBuf::from("foo")]);
+        assert_eq!(edits.create, vec![PathBuf::from("new/dir/")]);
+    }
+
+    #[test]
+    fn invalid_buffers() {
+        let cases = [
+            // Deleted line
+            "1\tfoo\n2\tbar\n",
+            // Added file
+            "1\tfoo\n2\tbar\n3\tbaz\nqux\n",
+            // Unknown and repeated ids
+            "1\tfoo\n2\tbar\n4\tbaz\n",
+            "1\tfoo\n2\tbar\n2\tbaz\n",
+        ];
+
+        for contents in cases.iter() {
+            assert!(parse(contents, &FILES).is_err());
+        }
+    }
+
+    #[test]
+    fn whitespace_path() {
+        assert_eq!(
+            parse("1\t   \n2\tbar\n3\t\t\n", &FILES).unwrap_err(),
+            "Line 1: empty path. Prefix the line with ! to move it to the trash.\n\
+             Line 3: empty path. Prefix the line with ! to move it to the trash."
+        );
+    }
+
+    #[test]
+    fn report_all_problems() {
+        let files = ["foo", "bar", "baz", "qux", "a", "b"];
+        let contents = "1\tx\n2\t\n3\tx\nx/\n5\ta/b\n6\ty\n6\tz\nnew\n9\tc\n";
+
+        assert_eq!(
+            parse(contents, &files).unwrap_err(),
+            "Lines 1, 3, 4: duplicate destination x\n\
+             Line 2: empty path. Prefix the line with ! to move it to the trash.\n\
+             Line 5: cannot move a into itself (a/b)\n\
+             Line 7: id 6 is used twice\n\
+             Line 8: missing id. Only directories (ending in /) can be added.\n\
+             Line 9: unknown id 9\n\
+             Do not delete lines (qux missing). \
+             Prefix a line with ! to move it to the trash."
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! Runs the user's editor on the edit buffer.
+//!
+//! The command is taken from the first non-empty variable among
+//! `MMV_EDITOR`, `VISUAL` and `EDITOR`, or is `vi`. It is split into
+//! words like a POSIX shell would, so quoted program paths and
+//! arguments work, Eg. `"/opt/My Editor/bin/ed" -f`, but no expansion
+//! or redirection is performed.
+
+use std::env;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Variables holding the editor command, by precedence.
+pub const VARS: [&str; 3] = ["MMV_EDITOR", "VISUAL", "EDITOR"];
+
+/// Editor used when none of `VARS` is set.
+pub const DEFAULT: &str = "vi";
+
+/// Returns the editor command, looking variables up with var.
+pub fn command<F: Fn(&str) -> Option<String>>(var: F) -> String {
+    VARS.iter()
+        .filter_map(|name| var(name))
+        .find(|command| !command.trim().is_empty())
+        .unwrap_or_else(|| String::from(DEFAULT))
+}
+
+/// Splits command into words, following the quoting rules of a POSIX
+/// shell: backslash escapes the next character, single quotes keep
+/// everything literally and double quotes only let backslash escape
+/// `$`, `` ` ``, `"`, `\` and newline.
+pub fn split(command: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::<String>::new();
+    // Quotes can make an empty word, so track words separately.
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => words.extend(word.take()),
+            '\\' => match chars.next() {
+                // Line continuation.
+                Some('\n') => {}
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => word.get_or_insert_with(String::new).push('\\'),
+            },
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(unterminated(command)),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('\n') => {}
+                            Some(c @ ('$' | '`' | '"' | '\\')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(unterminated(command)),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(unterminated(command)),
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+
+    Ok(words)
+}
+
+fn unterminated(command: &str) -> String {
+    format!("Unterminated quote in editor command {}", command)
+}
+
+/// Opens path in the user's editor and waits for it to exit.
+pub fn edit(path: &Path) -> Result<(), String> {
+    open(&command(|name| env::var(name).ok()), path)
+}
+
+/// Runs command with path as its last argument. Failing to start, or a
+/// non-zero exit status, is an error.
+fn open(command: &str, path: &Path) -> Result<(), String> {
+    let words = split(command)?;
+    let (program, args) = match words.split_first() {
+        Some((program, args)) => (program, args),
+        None => return Err(String::from("Empty editor command")),
+    };
+
+    let status = Command::new(program)
+        .args(args)
+        .arg(path)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .map_err(|err| format!("Error executing editor {}:\n{}", program, err))?;
+    if !status.success() {
+        return Err(format!("Editor {} failed ({})", program, status));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use super::{command, open, split};
+
+    #[test]
+    fn editor_precedence() {
+        let command_with = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<String, String>>();
+            command(|name| vars.get(name).cloned())
+        };
+
+        assert_eq!(command_with(&[]), "vi");
+        assert_eq!(command_with(&[("EDITOR", "nano")]), "nano");
+        assert_eq!(
+            command_with(&[("EDITOR", "nano"), ("VISUAL", "code --wait")]),
+            "code --wait"
+        );
+        assert_eq!(
+            command_with(&[("EDITOR", "nano"), ("VISUAL", "code"), ("MMV_EDITOR", "ed")]),
+            "ed"
+        );
+        // Empty variables are skipped.
+        assert_eq!(command_with(&[("EDITOR", "nano"), ("VISUAL", " ")]), "nano");
+        assert_eq!(command_with(&[("EDITOR", "")]), "vi");
+    }
+
+    #[test]
+    fn split_words() {
+        let cases = [
+            ("vi", vec!["vi"]),
+            ("  code   --wait ", vec!["code", "--wait"]),
+            (
+                r#""/opt/My Editor/bin/ed" -f"#,
+                vec!["/opt/My Editor/bin/ed", "-f"],
+            ),
+            ("'it''s' a\\ b", vec!["its", "a b"]),
+            (r#"ed -c 'set x="$y"'"#, vec!["ed", "-c", r#"set x="$y""#]),
+            (r#"ed "a\"b\\c\d\$""#, vec!["ed", r#"a"b\c\d$"#]),
+            ("ed '' \"\"", vec!["ed", "", ""]),
+            ("ed \\\n-f", vec!["ed", "-f"]),
+            ("ed a\\", vec!["ed", "a\\"]),
+            ("", vec![]),
+        ];
+
+        for (command, words) in cases.iter() {
+            assert_eq!(split(command).unwrap(), *words, "{}", command);
+        }
+        for command in &["ed 'a", "ed \"a", "ed \"a\\"] {
+            assert_eq!(
+                split(command),
+                Err(format!("Unterminated quote in editor command {}", command))
+            );
+        }
+    }
+
+    #[test]
+    fn editor_exit_status() {
+        let path = Path::new("buffer.txt");
+
+        assert_eq!(open("true", path), Ok(()));
+        // The path comes last, after the arguments.
+        assert_eq!(open("sh -c 'test \"$0\" = buffer.txt'", path), Ok(()));
+        assert_eq!(
+            open("sh -c 'exit 3'", path),
+            Err(String::from("Editor sh failed (exit status: 3)"))
+        );
+        assert!(open("'/nonexistent/my editor'", path)
+            .unwrap_err()
+            .starts_with("Error executing editor /nonexistent/my editor:\n"));
+        assert_eq!(open(" ", path), Err(String::from("Empty editor command")));
+    }
+}
@@ -20,12 +20,13 @@ mod linux;
 mod unix;
 mod windows;
 
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
 
 pub fn os_separator() -> char {
     if cfg!(unix) {
         return unix::PATH_SEPARATOR;
-    } else if cfg!(linux) {
+    } else if cfg!(target_os = "linux") {
         return linux::PATH_SEPARATOR;
     } else if cfg!(windows) {
         return windows::PATH_SEPARATOR;
@@ -38,7 +39,7 @@ pub fn os_separator() -> char {
 pub fn is_path_separator(c: char) -> bool {
     if cfg!(unix) {
         return unix::is_path_separator(c);
-    } else if cfg!(linux) {
+    } else if cfg!(target_os = "linux") {
         return linux::is_path_separator(c);
     } else if cfg!(windows) {
         return windows::is_path_separator(c);
@@ -70,116 +71,119 @@ pub fn from_slash<P: AsRef<Path>>(path: P) -> PathBuf {
     PathBuf::from(new_path)
 }
 
+/// Returns the shortest path equivalent to `path` by purely lexical
+/// processing (See golang's `filepath.Clean`).
+///
+/// Works on the raw bytes of the path, so paths which are not valid
+/// UTF-8 are supported and no per-character buffer is allocated.
 pub fn clean<P: AsRef<Path>>(path: P) -> PathBuf {
-    let path_vec = path
-        .as_ref()
-        .to_str()
-        .unwrap()
-        .chars()
-        .collect::<Vec<char>>();
-    let vol_len = volume_name_len(path.as_ref());
-    let path_without_vol = &path_vec[vol_len..path_vec.len()];
+    let path = path.as_ref();
+    let bytes = path.as_os_str().as_encoded_bytes();
+    let vol_len = volume_name_len(path);
+    let path_without_vol = &bytes[vol_len..];
 
     if path_without_vol.is_empty() {
-        if vol_len > 1 && path_vec[1] != ':' {
+        if vol_len > 1 && bytes[1] != b':' {
             // UNC pathing probably.
-            return from_slash(path.as_ref());
+            return from_slash(path);
         }
 
         // For empty paths, return prefix + ".".
-        let mut new_path = path.as_ref().to_path_buf();
+        let mut new_path = path.to_path_buf();
         new_path.push(".");
         return new_path;
     }
 
+    // Separators are ASCII, so testing single bytes is enough.
+    let is_sep = |c: u8| c.is_ascii() && is_path_separator(c as char);
+    let separator = os_separator() as u8;
+
     let (mut r, mut dotdot) = (0, 0);
     let n = path_without_vol.len();
-    let rooted = is_path_separator(path_without_vol[0]);
+    let rooted = is_sep(path_without_vol[0]);
 
     // Actual implementation uses a lazy buffer to save space
     // by reusing the path.
     //
     // But for simplicity, I will use a simple vector for now.
-    let mut out = Vec::<char>::new();
+    let mut out = Vec::<u8>::with_capacity(n);
 
     if rooted {
-        out.push(os_separator());
+        out.push(separator);
         r = 1;
         dotdot = 1;
     }
 
     while r < n {
-        if is_path_separator(path_without_vol[r]) {
+        if is_sep(path_without_vol[r]) {
             // Empty path element
             r += 1;
-        } else if path_without_vol[r] == '.'
-            && (r + 1 == n || is_path_separator(path_without_vol[r + 1]))
-        {
+        } else if path_without_vol[r] == b'.' && (r + 1 == n || is_sep(path_without_vol[r + 1])) {
             // '.' element followed by '/' or the next char is the end of path.
             r += 1;
-        } else if path_without_vol[r] == '.'
-            && path_without_vol[r + 1] == '.'
-            && (r + 2 == n || is_path_separator(path_without_vol[r + 2]))
+        } else if path_without_vol[r] == b'.'
+            && path_without_vol[r + 1] == b'.'
+            && (r + 2 == n || is_sep(path_without_vol[r + 2]))
         {
             // .. element: remove to last separator
             r += 2;
-            if out.len() > dotdot + 1 {
-                out.pop();
-                while let Some(&last_char) = out.last() {
-                    if out.len() <= dotdot || is_path_separator(last_char) {
+            if out.len() > dotdot {
+                // Drop the last element and its separator, but never
+                // the root or leading `..` elements.
+                while out.len() > dotdot {
+                    if is_sep(out.pop().unwrap()) {
                         break;
                     }
-                    out.pop();
                 }
             } else if !rooted {
-                if out.len() > 0 {
-                    if let Some(&last_char) = out.last() {
-                        if !is_path_separator(last_char) {
-                            out.push(os_separator());
-                        }
+                if let Some(&last_char) = out.last() {
+                    if !is_sep(last_char) {
+                        out.push(separator);
                     }
                 }
-                out.push('.');
-                out.push('.');
+                out.extend_from_slice(b"..");
                 dotdot = out.len();
             }
         } else {
             // Default
 
-            if rooted && out.len() != 1 || !rooted && out.len() != 0 {
+            if rooted && out.len() != 1 || !rooted && !out.is_empty() {
                 // In lazybuf, append does neccessarily mean push new character
                 // onto array but instead could mean reusing latest character.
                 //
                 // So only push separator if last character isn't.
                 if let Some(&last_char) = out.last() {
-                    if !is_path_separator(last_char) {
-                        out.push(os_separator());
+                    if !is_sep(last_char) {
+                        out.push(separator);
                     }
                 }
             }
 
             // Copy non-separator characters.
-            while r < n && !is_path_separator(path_without_vol[r]) {
-                out.push(path_without_vol[r]);
+            let start = r;
+            while r < n && !is_sep(path_without_vol[r]) {
                 r += 1;
             }
+            out.extend_from_slice(&path_without_vol[start..r]);
         }
     }
 
-    if out.len() == 0 {
-        out.push('.');
+    if out.is_empty() {
+        out.push(b'.');
     }
 
     // Remove any last separator since
     // expected clean path looks like this:
     // {path prefix}/{some name}
     if let Some(&last_char) = out.last() {
-        if out.len() > 1 && is_path_separator(last_char) {
+        if out.len() > 1 && is_sep(last_char) {
             out.pop();
         }
     }
 
-    PathBuf::from(out.into_iter().collect::<String>())
+    // SAFETY: out only holds whole runs of bytes from path, split at
+    // ASCII separators, and ASCII bytes.
+    PathBuf::from(unsafe { OsString::from_encoded_bytes_unchecked(out) })
 }
 
 #[test]
@@ -228,6 +232,9 @@ fn test_clean() {
             ("/abc/def/../../..", "/"),
             ("abc/def/../../../ghi/jkl/../../../mno", "../../mno"),
             ("/../abc", "/abc"),
+            ("abc/..", "."),
+            ("abc/../", "."),
+            ("../abc/../..", "../.."),
             // Combinations
             ("abc/./../def", "def"),
             ("abc//./../def", "def"),
@@ -240,6 +247,19 @@ fn test_clean() {
     }
 }
 
+#[cfg(unix)]
+#[test]
+fn test_clean_non_utf8() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = OsStr::from_bytes(b"./caf\xe9//x/../\xff/");
+    assert_eq!(
+        clean(path).as_os_str().as_bytes(),
+        OsStr::from_bytes(b"caf\xe9/\xff").as_bytes()
+    );
+}
+
 #[test]
 fn test_from_slash() {
     let str_path = "/test_dir/file.index.html";
@@ -253,3 +273,29 @@ fn test_from_slash() {
         assert_eq!(path, final_path);
     }
 }
+
+#[cfg(all(test, unix))]
+proptest::proptest! {
+    #[test]
+    fn test_clean_properties(path in "[ab./]{0,16}") {
+        let cleaned = clean(&path);
+        let cleaned_str = cleaned.to_str().unwrap();
+
+        // Cleaning twice changes nothing.
+        proptest::prop_assert_eq!(clean(&cleaned), cleaned.clone());
+
+        proptest::prop_assert!(!cleaned_str.is_empty());
+        proptest::prop_assert!(!cleaned_str.contains("//"));
+        proptest::prop_assert!(cleaned_str == "/" || !cleaned_str.ends_with('/'));
+        if cleaned_str != "." {
+            proptest::prop_assert!(cleaned_str.split('/').all(|name| name != "."));
+        }
+        // `..` only remains at the start of relative paths, since it
+        // cannot go above the root.
+        let names = cleaned_str.split('/').collect::<Vec<&str>>();
+        let leading = names.iter().take_while(|&&name| name == "..").count();
+        proptest::prop_assert!(names[leading..].iter().all(|&name| name != ".."));
+        proptest::prop_assert!(!(cleaned_str.starts_with('/') && leading > 0));
+        proptest::prop_assert_eq!(cleaned_str.starts_with('/'), path.starts_with('/'));
+    }
+}
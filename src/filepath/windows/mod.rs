@@ -11,10 +11,7 @@ pub fn is_path_separator(c: char) -> bool {
 /// volumeNameLen returns length of the leading volume name on Windows.
 /// It returns 0 elsewhere.
 pub fn volume_name_len(path: &Path) -> usize {
-    let path_str = match path.to_str() {
-        Some(path_str) => path_str,
-        None => "",
-    };
+    let path_str = path.to_str().unwrap_or_default();
 
     // Rust represents strings as UTF-8 internally.
     //
@@ -31,7 +28,7 @@ pub fn volume_name_len(path: &Path) -> usize {
 
     // Check for volume names such as
     // "C:\".
-    if path_vec[0] == ':' || ('a' <= c && c <= 'z') || ('A' <= c && c <= 'Z') {
+    if path_vec[0] == ':' || c.is_ascii_lowercase() || c.is_ascii_uppercase() {
         return 2;
     }
 
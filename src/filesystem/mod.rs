@@ -0,0 +1,620 @@
+//! Filesystem operations used to execute renames.
+//!
+//! `OsFs` calls the operating system. `MemFs` keeps a tree in memory,
+//! so that renames can be tested without touching the disk and errors
+//! can be injected on any path, Eg. EXDEV when a rename crosses
+//! filesystems.
+//!
+//! Errors mimic those of the operating system, down to the error code,
+//! so that messages are the same with either implementation.
+//!
+//! `MemFs::load()` copies part of the real tree, which lets a dry run
+//! execute renames for real without touching the disk.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::ops::Bound;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::filepath::clean;
+
+/// What renaming needs to know about a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    /// Size in bytes, 0 for directories.
+    pub len: u64,
+}
+
+pub trait Filesystem: Sync {
+    /// Renames `src` to `dst`, replacing `dst` if it exists, like
+    /// `rename(2)`.
+    fn rename(&self, src: &Path, dst: &Path) -> io::Result<()>;
+
+    /// Same as `rename()` but fails with EEXIST if `dst` exists.
+    fn rename_noreplace(&self, src: &Path, dst: &Path) -> io::Result<()>;
+
+    /// Returns the metadata of path, following symbolic links.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Same as `metadata()` but does not follow symbolic links, so a
+    /// dangling one exists.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Creates path and all of its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Removes the empty directory path.
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Atomically swaps `a` and `b`, which must both exist.
+    fn exchange(&self, a: &Path, b: &Path) -> io::Result<()>;
+}
+
+/// The filesystem of the operating system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsFs;
+
+impl Filesystem for OsFs {
+    fn rename(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        fs::rename(src, dst)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn rename_noreplace(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        renameat2(src, dst, libc::RENAME_NOREPLACE)
+    }
+
+    /// Checking first is racy, but no better is available here.
+    #[cfg(not(target_os = "linux"))]
+    fn rename_noreplace(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        match fs::symlink_metadata(dst) {
+            Ok(_metadata) => Err(io::Error::from_raw_os_error(libc::EEXIST)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => fs::rename(src, dst),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = fs::metadata(path)?;
+        Ok(Metadata {
+            is_dir: metadata.is_dir(),
+            len: if metadata.is_dir() { 0 } else { metadata.len() },
+        })
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = fs::symlink_metadata(path)?;
+        Ok(Metadata {
+            is_dir: metadata.is_dir(),
+            len: if metadata.is_dir() { 0 } else { metadata.len() },
+        })
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn exchange(&self, a: &Path, b: &Path) -> io::Result<()> {
+        renameat2(a, b, libc::RENAME_EXCHANGE)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn exchange(&self, _a: &Path, _b: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Exchanging paths is only supported on Linux",
+        ))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn renameat2(src: &Path, dst: &Path, flags: libc::c_uint) -> io::Result<()> {
+    let c_path = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    };
+    let (src, dst) = (c_path(src)?, c_path(dst)?);
+    // SAFETY: both paths are valid nul-terminated strings.
+    let res = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            src.as_ptr(),
+            libc::AT_FDCWD,
+            dst.as_ptr(),
+            flags,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// A file with its contents, or a directory with a label, Eg. to
+/// know where it came from. Directories made by `create_dir_all()`
+/// have an empty label.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node {
+    File(Vec<u8>),
+    Dir(Vec<u8>),
+}
+
+/// An in-memory filesystem holding files and directories.
+///
+/// Paths are cleaned first, and `.` and `/` always exist, so relative
+/// and absolute paths are two separate trees.
+#[derive(Debug, Default)]
+pub struct MemFs {
+    nodes: Mutex<BTreeMap<PathBuf, Node>>,
+    /// Error code returned when renaming from a path.
+    failures: Mutex<HashMap<PathBuf, i32>>,
+    /// Directories whose entries cannot change.
+    protected: Mutex<HashSet<PathBuf>>,
+}
+
+impl MemFs {
+    pub fn new() -> Self {
+        MemFs::default()
+    }
+
+    /// Copies `paths` and their ancestors from the operating system.
+    /// Files and directories are labelled with their own path, and the
+    /// contents of files are not read.
+    ///
+    /// Directories without write permission are protected, and those
+    /// in `paths` which are not empty get one of their entries, so that
+    /// replacing them fails like it would on disk.
+    pub fn load<P: AsRef<Path>>(paths: &[P]) -> io::Result<MemFs> {
+        let fs = MemFs::new();
+        let mut seen = BTreeSet::<PathBuf>::new();
+        for path in paths {
+            let path = clean(path);
+            for ancestor in path.ancestors() {
+                let ancestor = clean(ancestor);
+                if seen.insert(ancestor.clone()) {
+                    fs.load_path(&ancestor, ancestor == path)?;
+                }
+            }
+        }
+
+        // Only protect directories once everything inside is loaded.
+        let roots = vec![PathBuf::from("."), PathBuf::from("/")];
+        for dir in fs.dirs().into_iter().chain(roots) {
+            if seen.contains(&dir) && !writable(&dir) {
+                fs.protect(dir);
+            }
+        }
+        Ok(fs)
+    }
+
+    fn load_path(&self, path: &Path, with_entry: bool) -> io::Result<()> {
+        let label = path.as_os_str().as_encoded_bytes();
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if !metadata.is_dir() {
+            return self.write(path, label);
+        }
+
+        self.create_dir(path, label)?;
+        if with_entry {
+            if let Some(entry) = fs::read_dir(path)?.next() {
+                let entry = path.join(entry?.file_name());
+                let mut nodes = self.nodes.lock().unwrap();
+                let label = entry.as_os_str().as_encoded_bytes().to_vec();
+                nodes.entry(entry).or_insert(Node::File(label));
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the file path with contents, and its missing parents.
+    pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(&self, path: P, contents: C) -> io::Result<()> {
+        let path = clean(path);
+        self.create_dir_all(&parent(&path))?;
+
+        let mut nodes = self.nodes.lock().unwrap();
+        if let Some(Node::Dir(_)) = nodes.get(&path) {
+            return Err(os_error(libc::EISDIR));
+        }
+        nodes.insert(path, Node::File(contents.as_ref().to_vec()));
+        Ok(())
+    }
+
+    /// Creates the directory path with label, and its missing parents.
+    pub fn create_dir<P: AsRef<Path>>(&self, path: P, label: &[u8]) -> io::Result<()> {
+        let path = clean(path);
+        self.create_dir_all(&path)?;
+        if !is_root(&path) {
+            let mut nodes = self.nodes.lock().unwrap();
+            nodes.insert(path, Node::Dir(label.to_vec()));
+        }
+        Ok(())
+    }
+
+    /// Removes path and everything inside it, like moving it to the
+    /// trash.
+    pub fn remove_all<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = clean(path);
+        let mut nodes = self.nodes.lock().unwrap();
+        if kind(&nodes, &path).is_none() {
+            return Err(os_error(libc::ENOENT));
+        }
+        if is_root(&path) {
+            return Err(os_error(libc::EBUSY));
+        }
+        self.check_writable(&parent(&path))?;
+        take_tree(&mut nodes, &path);
+        Ok(())
+    }
+
+    /// Prevents adding or removing entries of dir, like a directory
+    /// without write permission.
+    pub fn protect<P: AsRef<Path>>(&self, dir: P) {
+        self.protected.lock().unwrap().insert(clean(dir));
+    }
+
+    /// Makes every rename from src fail with the error code errno.
+    pub fn fail<P: AsRef<Path>>(&self, src: P, errno: i32) {
+        self.failures.lock().unwrap().insert(clean(src), errno);
+    }
+
+    /// Returns every file with its contents, sorted by path.
+    pub fn files(&self) -> Vec<(PathBuf, String)> {
+        self.nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(path, node)| match node {
+                Node::File(contents) => {
+                    Some((path.clone(), String::from_utf8_lossy(contents).into_owned()))
+                }
+                Node::Dir(_) => None,
+            })
+            .collect()
+    }
+
+    /// Returns every directory, sorted.
+    pub fn dirs(&self) -> Vec<PathBuf> {
+        self.nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, node)| matches!(node, Node::Dir(_)))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Returns the contents of a file, or the label of a directory.
+    pub fn contents<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        match self.nodes.lock().unwrap().get(&clean(path))? {
+            Node::File(contents) | Node::Dir(contents) => Some(contents.clone()),
+        }
+    }
+
+    /// Returns the contents of every file and the label of every
+    /// directory, with their paths.
+    pub fn entries(&self) -> Vec<(PathBuf, Vec<u8>)> {
+        self.nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, node)| match node {
+                Node::File(contents) | Node::Dir(contents) => (path.clone(), contents.clone()),
+            })
+            .collect()
+    }
+
+    fn check_writable(&self, dir: &Path) -> io::Result<()> {
+        if self.protected.lock().unwrap().contains(dir) {
+            return Err(os_error(libc::EACCES));
+        }
+        Ok(())
+    }
+
+    /// `rename()`, or `rename_noreplace()` if noreplace.
+    fn move_path(&self, src: &Path, dst: &Path, noreplace: bool) -> io::Result<()> {
+        let (src, dst) = (clean(src), clean(dst));
+        if let Some(&errno) = self.failures.lock().unwrap().get(&src) {
+            return Err(os_error(errno));
+        }
+
+        let mut nodes = self.nodes.lock().unwrap();
+        let src_node = kind(&nodes, &src).ok_or_else(|| os_error(libc::ENOENT))?;
+        match kind(&nodes, &parent(&dst)) {
+            None => return Err(os_error(libc::ENOENT)),
+            Some(Node::File(_)) => return Err(os_error(libc::ENOTDIR)),
+            Some(Node::Dir(_)) => {}
+        }
+        if noreplace && kind(&nodes, &dst).is_some() {
+            return Err(os_error(libc::EEXIST));
+        }
+        if src == dst {
+            return Ok(());
+        }
+        self.check_writable(&parent(&src))?;
+        self.check_writable(&parent(&dst))?;
+        if is_root(&src) || dst.starts_with(&src) {
+            return Err(os_error(libc::EINVAL));
+        }
+
+        match (&src_node, kind(&nodes, &dst)) {
+            (_, None) => {}
+            (Node::File(_), Some(Node::File(_))) => {}
+            (Node::File(_), Some(Node::Dir(_))) => return Err(os_error(libc::EISDIR)),
+            (Node::Dir(_), Some(Node::File(_))) => return Err(os_error(libc::ENOTDIR)),
+            (Node::Dir(_), Some(Node::Dir(_))) => {
+                if has_children(&nodes, &dst) {
+                    return Err(os_error(libc::ENOTEMPTY));
+                }
+            }
+        }
+
+        nodes.remove(&dst);
+        for (path, node) in take_tree(&mut nodes, &src) {
+            nodes.insert(rebase(&path, &src, &dst), node);
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for MemFs {
+    fn rename(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.move_path(src, dst, false)
+    }
+
+    fn rename_noreplace(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.move_path(src, dst, true)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let nodes = self.nodes.lock().unwrap();
+        match kind(&nodes, &clean(path)) {
+            None => Err(os_error(libc::ENOENT)),
+            Some(Node::Dir(_)) => Ok(Metadata {
+                is_dir: true,
+                len: 0,
+            }),
+            Some(Node::File(contents)) => Ok(Metadata {
+                is_dir: false,
+                len: contents.len() as u64,
+            }),
+        }
+    }
+
+    /// There are no symbolic links in memory.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.metadata(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let path = clean(path);
+        let mut nodes = self.nodes.lock().unwrap();
+        // Outermost ancestor first.
+        let ancestors = path.ancestors().collect::<Vec<&Path>>();
+        for dir in ancestors.into_iter().rev() {
+            if dir.as_os_str().is_empty() || is_root(dir) {
+                continue;
+            }
+            match nodes.get(dir) {
+                None => {
+                    self.check_writable(&parent(dir))?;
+                    nodes.insert(dir.to_path_buf(), Node::Dir(Vec::new()));
+                }
+                Some(Node::Dir(_)) => {}
+                Some(Node::File(_)) => return Err(os_error(libc::EEXIST)),
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let path = clean(path);
+        let mut nodes = self.nodes.lock().unwrap();
+        match kind(&nodes, &path) {
+            None => Err(os_error(libc::ENOENT)),
+            Some(Node::File(_)) => Err(os_error(libc::ENOTDIR)),
+            Some(Node::Dir(_)) if is_root(&path) => Err(os_error(libc::EBUSY)),
+            Some(Node::Dir(_)) if has_children(&nodes, &path) => Err(os_error(libc::ENOTEMPTY)),
+            Some(Node::Dir(_)) => {
+                self.check_writable(&parent(&path))?;
+                nodes.remove(&path);
+                Ok(())
+            }
+        }
+    }
+
+    fn exchange(&self, a: &Path, b: &Path) -> io::Result<()> {
+        let (a, b) = (clean(a), clean(b));
+        let mut nodes = self.nodes.lock().unwrap();
+        if kind(&nodes, &a).is_none() || kind(&nodes, &b).is_none() {
+            return Err(os_error(libc::ENOENT));
+        }
+        if a == b {
+            return Ok(());
+        }
+        if is_root(&a) || is_root(&b) || a.starts_with(&b) || b.starts_with(&a) {
+            return Err(os_error(libc::EINVAL));
+        }
+        self.check_writable(&parent(&a))?;
+        self.check_writable(&parent(&b))?;
+
+        let (tree_a, tree_b) = (take_tree(&mut nodes, &a), take_tree(&mut nodes, &b));
+        for (path, node) in tree_a {
+            nodes.insert(rebase(&path, &a, &b), node);
+        }
+        for (path, node) in tree_b {
+            nodes.insert(rebase(&path, &b, &a), node);
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if entries can be added to or removed from dir.
+fn writable(dir: &Path) -> bool {
+    let c_dir = match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(c_dir) => c_dir,
+        Err(_err) => return false,
+    };
+    // SAFETY: c_dir is a valid NUL terminated string.
+    unsafe { libc::access(c_dir.as_ptr(), libc::W_OK | libc::X_OK) == 0 }
+}
+
+fn os_error(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+fn is_root(path: &Path) -> bool {
+    path == Path::new(".") || path == Path::new("/")
+}
+
+/// Returns the parent of a clean path, `.` for relative paths without
+/// one.
+fn parent(path: &Path) -> PathBuf {
+    clean(path.parent().unwrap_or_else(|| Path::new(path)))
+}
+
+fn kind(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> Option<Node> {
+    if is_root(path) {
+        return Some(Node::Dir(Vec::new()));
+    }
+    nodes.get(path).cloned()
+}
+
+fn has_children(nodes: &BTreeMap<PathBuf, Node>, dir: &Path) -> bool {
+    inside(nodes, dir).next().is_some()
+}
+
+/// Returns every path inside dir. Paths are ordered component by
+/// component, so they directly follow dir.
+fn inside<'a>(
+    nodes: &'a BTreeMap<PathBuf, Node>,
+    dir: &'a Path,
+) -> impl Iterator<Item = &'a PathBuf> + 'a {
+    nodes
+        .range::<Path, _>((Bound::Excluded(dir), Bound::Unbounded))
+        .map(|(path, _)| path)
+        .take_while(move |path| path.starts_with(dir))
+}
+
+/// Moves path, which is `from` or inside it, to `to`.
+fn rebase(path: &Path, from: &Path, to: &Path) -> PathBuf {
+    match path.strip_prefix(from) {
+        Ok(rest) if !rest.as_os_str().is_empty() => to.join(rest),
+        _ => to.to_path_buf(),
+    }
+}
+
+/// Removes path and everything inside it, returning them.
+fn take_tree(nodes: &mut BTreeMap<PathBuf, Node>, path: &Path) -> Vec<(PathBuf, Node)> {
+    let mut paths = inside(nodes, path).cloned().collect::<Vec<PathBuf>>();
+    paths.push(path.to_path_buf());
+    paths
+        .into_iter()
+        .filter_map(|path| nodes.remove(&path).map(|node| (path, node)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{Filesystem, MemFs, Metadata};
+
+    fn files(fs: &MemFs) -> Vec<(String, String)> {
+        fs.files()
+            .into_iter()
+            .map(|(path, contents)| (path.to_str().unwrap().to_string(), contents))
+            .collect()
+    }
+
+    #[test]
+    fn rename_files_and_dirs() {
+        let fs = MemFs::new();
+        fs.write("a", "0").unwrap();
+        fs.write("b", "1").unwrap();
+        fs.write("dir/c", "2").unwrap();
+
+        // Replaces an existing file.
+        fs.rename("a".as_ref(), "./b".as_ref()).unwrap();
+        // Moves everything inside a directory.
+        fs.rename("dir".as_ref(), "new".as_ref()).unwrap();
+
+        assert_eq!(
+            files(&fs),
+            vec![
+                (String::from("b"), String::from("0")),
+                (String::from("new/c"), String::from("2")),
+            ]
+        );
+        assert_eq!(fs.dirs(), vec![PathBuf::from("new")]);
+        assert_eq!(
+            fs.metadata("new".as_ref()).unwrap(),
+            Metadata {
+                is_dir: true,
+                len: 0
+            }
+        );
+    }
+
+    #[test]
+    fn rename_errors() {
+        let fs = MemFs::new();
+        fs.write("a", "0").unwrap();
+        fs.write("dir/b", "1").unwrap();
+        fs.fail("dir/b", libc::EXDEV);
+
+        let error = |src: &str, dst: &str| {
+            fs.rename(src.as_ref(), dst.as_ref())
+                .unwrap_err()
+                .raw_os_error()
+        };
+        assert_eq!(error("missing", "x"), Some(libc::ENOENT));
+        assert_eq!(error("a", "missing/a"), Some(libc::ENOENT));
+        assert_eq!(error("a", "a/x"), Some(libc::ENOTDIR));
+        assert_eq!(error("dir", "dir/sub"), Some(libc::EINVAL));
+        assert_eq!(error("a", "dir"), Some(libc::EISDIR));
+        assert_eq!(error("dir/b", "c"), Some(libc::EXDEV));
+        assert_eq!(
+            fs.rename_noreplace("a".as_ref(), "dir/b".as_ref())
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EEXIST)
+        );
+        assert_eq!(
+            fs.remove_dir("dir".as_ref()).unwrap_err().raw_os_error(),
+            Some(libc::ENOTEMPTY)
+        );
+    }
+
+    #[test]
+    fn exchange_paths() {
+        let fs = MemFs::new();
+        fs.write("a", "0").unwrap();
+        fs.write("dir/b", "1").unwrap();
+
+        fs.exchange("a".as_ref(), "dir".as_ref()).unwrap();
+
+        assert_eq!(
+            files(&fs),
+            vec![
+                (String::from("a/b"), String::from("1")),
+                (String::from("dir"), String::from("0")),
+            ]
+        );
+        fs.remove_dir("dir".as_ref()).unwrap_err();
+    }
+}
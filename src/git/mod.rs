@@ -0,0 +1,315 @@
+//! Keeps the git index in sync with renamed paths.
+//!
+//! Plain `fs::rename` leaves the index untouched, so git sees the
+//! old paths as deleted and the new ones as untracked. This module
+//! does what `git mv` does: every index entry under a renamed path
+//! is moved to the new path, keeping its staged blob and mode.
+//!
+//! The index is updated from the requested mapping (not the executed
+//! edges), so temporary names used to break cycles never reach it.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use super::filepath::clean;
+
+static NULL_SHA: &str = "0000000000000000000000000000000000000000";
+
+#[derive(Debug)]
+struct IndexEntry {
+    mode: String,
+    sha: String,
+    path: String,
+}
+
+/// Index changes computed before the files are renamed.
+#[derive(Debug)]
+pub struct IndexUpdate {
+    toplevel: PathBuf,
+    // (old entry, new path relative to toplevel)
+    moves: Vec<(IndexEntry, String)>,
+}
+
+/// Checks that no destination of a tracked source is ignored or would
+/// clobber a path outside the rename set, then returns the index
+/// changes equivalent to `git mv`. Untracked sources are renamed on
+/// disk only, so they are left out.
+pub fn plan<P: AsRef<Path>>(files: &HashMap<P, P>) -> Result<IndexUpdate, String> {
+    let cwd = env::current_dir().map_err(|err| err.to_string())?;
+    let cwd = cwd.canonicalize().map_err(|err| err.to_string())?;
+
+    let mut moves = files
+        .iter()
+        .map(|(src, dst)| (clean(cwd.join(src)), clean(cwd.join(dst))))
+        .filter(|(src, dst)| src != dst)
+        .collect::<Vec<(PathBuf, PathBuf)>>();
+    moves.sort();
+
+    // All paths must belong to the repository of the first source.
+    let repo_dir = match moves.first().and_then(|(src, _)| src.parent()) {
+        Some(dir) => dir.to_path_buf(),
+        None => cwd,
+    };
+    let toplevel = git(&repo_dir, &["rev-parse", "--show-toplevel"], None)?;
+    let toplevel = PathBuf::from(toplevel.trim_end())
+        .canonicalize()
+        .map_err(|err| err.to_string())?;
+
+    let mut errors = Vec::<String>::new();
+    let mut update = IndexUpdate {
+        toplevel: toplevel.clone(),
+        moves: Vec::new(),
+    };
+
+    for (src, dst) in &moves {
+        // Sources outside the repository are not tracked either.
+        let rel_src = match relative(&toplevel, src) {
+            Some(rel_src) => rel_src,
+            None => continue,
+        };
+        let entries = index_entries(&toplevel, &rel_src)?;
+        if entries.is_empty() {
+            continue;
+        }
+
+        let rel_dst = match relative(&toplevel, dst) {
+            Some(rel_dst) => rel_dst,
+            None => {
+                errors.push(format!("Outside repository {}", dst.display()));
+                continue;
+            }
+        };
+
+        if is_ignored(&toplevel, &rel_dst)? {
+            errors.push(format!("Destination is ignored by git {}", dst.display()));
+            continue;
+        }
+
+        // Like `git mv`, refuse to replace a path that is not itself
+        // being moved away.
+        if fs::symlink_metadata(dst).is_ok() && !moves.iter().any(|(src, _)| src == dst) {
+            errors.push(format!("Destination exists {}", dst.display()));
+            continue;
+        }
+
+        for entry in entries {
+            let new_path = format!("{}{}", rel_dst, &entry.path[rel_src.len()..]);
+            update.moves.push((entry, new_path));
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
+    Ok(update)
+}
+
+/// Moves index entries to their new paths. Must be called after the
+/// files were renamed on disk.
+pub fn apply(update: &IndexUpdate) -> Result<(), String> {
+    if update.moves.is_empty() {
+        return Ok(());
+    }
+
+    // Remove all old entries before adding new ones so that cycles
+    // (A -> B -> A) do not drop an entry that was just added.
+    let mut input = String::new();
+    for (entry, _) in &update.moves {
+        input.push_str(&format!("0 {}\t{}\0", NULL_SHA, entry.path));
+    }
+    for (entry, new_path) in &update.moves {
+        input.push_str(&format!("{} {}\t{}\0", entry.mode, entry.sha, new_path));
+    }
+
+    git(
+        &update.toplevel,
+        &["update-index", "-z", "--index-info"],
+        Some(&input),
+    )?;
+
+    Ok(())
+}
+
+/// Returns `path` relative to `toplevel` using `/` separators as git does.
+fn relative(toplevel: &Path, path: &Path) -> Option<String> {
+    let rel = path.strip_prefix(toplevel).ok()?;
+    let rel_str = rel.to_str()?;
+    if rel_str.is_empty() {
+        // The repository itself cannot be moved through the index.
+        return None;
+    }
+
+    Some(rel_str.replace('\\', "/"))
+}
+
+/// Returns all stage 0 entries at or below `path`.
+fn index_entries(toplevel: &Path, path: &str) -> Result<Vec<IndexEntry>, String> {
+    let output = git(
+        toplevel,
+        &[
+            "--literal-pathspecs",
+            "ls-files",
+            "-s",
+            "-z",
+            "--full-name",
+            "--",
+            path,
+        ],
+        None,
+    )?;
+
+    let mut entries = Vec::<IndexEntry>::new();
+    for record in output.split_terminator('\0') {
+        // Format: <mode> SP <sha> SP <stage> TAB <path>
+        let (info, entry_path) = match record.split_once('\t') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let fields = info.split(' ').collect::<Vec<&str>>();
+        if fields.len() != 3 {
+            continue;
+        }
+
+        if fields[2] != "0" {
+            return Err(format!("Unmerged path {}", entry_path));
+        }
+
+        entries.push(IndexEntry {
+            mode: fields[0].to_string(),
+            sha: fields[1].to_string(),
+            path: entry_path.to_string(),
+        });
+    }
+
+    Ok(entries)
+}
+
+fn is_ignored(toplevel: &Path, path: &str) -> Result<bool, String> {
+    let status = Command::new("git")
+        .current_dir(toplevel)
+        .args(["check-ignore", "-q", "--no-index", "--", path])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|err| format!("Error executing git:\n{}", err))?;
+
+    // check-ignore exits with 0 when ignored and 1 when not.
+    match status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => Err(format!("git check-ignore failed for {}", path)),
+    }
+}
+
+/// Runs git in `dir` and returns its standard output.
+fn git(dir: &Path, args: &[&str], input: Option<&str>) -> Result<String, String> {
+    let mut child = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("Error executing git:\n{}", err))?;
+
+    if let Some(input) = input {
+        if let Some(stdin) = child.stdin.as_mut() {
+            stdin
+                .write_all(input.as_bytes())
+                .map_err(|err| format!("Error executing git:\n{}", err))?;
+        }
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|err| format!("Error executing git:\n{}", err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed:\n{}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::super::ioutils::temp_dir;
+    use super::{git, index_entries, IndexUpdate};
+
+    fn tracked(dir: &Path) -> Vec<String> {
+        let mut paths = index_entries(dir, ".")
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect::<Vec<String>>();
+        paths.sort();
+        paths
+    }
+
+    fn plan_in(dir: &Path, files: &[(&str, &str)]) -> Result<IndexUpdate, String> {
+        // plan() works relative to the current directory, so use
+        // absolute paths instead of changing it for all tests.
+        let map = files
+            .iter()
+            .map(|&(src, dst)| (dir.join(src), dir.join(dst)))
+            .collect::<HashMap<PathBuf, PathBuf>>();
+        super::plan(&map)
+    }
+
+    #[test]
+    fn move_index_entries() {
+        let dir = PathBuf::from(temp_dir("", "mmv-git-").unwrap())
+            .canonicalize()
+            .unwrap();
+        git(&dir, &["init", "-q"], None).unwrap();
+        fs::create_dir(dir.join("d")).unwrap();
+        for file in &["foo", "bar", "d/baz"] {
+            fs::write(dir.join(file), file).unwrap();
+        }
+        fs::write(dir.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(dir.join("untracked"), "").unwrap();
+        git(&dir, &["add", "foo", "bar", "d", ".gitignore"], None).unwrap();
+
+        // Ignored and existing destinations are refused.
+        assert!(plan_in(&dir, &[("foo", "foo.log")]).is_err());
+        assert!(plan_in(&dir, &[("foo", "untracked")]).is_err());
+
+        // Untracked sources are left out of the index update.
+        let update = plan_in(&dir, &[("untracked", "other"), ("foo", "baz")]).unwrap();
+        let moved = update
+            .moves
+            .iter()
+            .map(|(entry, new_path)| (entry.path.as_str(), new_path.as_str()))
+            .collect::<Vec<(&str, &str)>>();
+        assert_eq!(moved, vec![("foo", "baz")]);
+
+        // Swap foo and bar and move a directory.
+        let files = [("foo", "bar"), ("bar", "foo"), ("d", "e")];
+        let update = plan_in(&dir, &files).unwrap();
+        fs::rename(dir.join("foo"), dir.join("tmp")).unwrap();
+        fs::rename(dir.join("bar"), dir.join("foo")).unwrap();
+        fs::rename(dir.join("tmp"), dir.join("bar")).unwrap();
+        fs::rename(dir.join("d"), dir.join("e")).unwrap();
+        super::apply(&update).unwrap();
+
+        assert_eq!(tracked(&dir), vec![".gitignore", "bar", "e/baz", "foo"]);
+        // Index contents follow the files, so nothing is modified.
+        let status = git(&dir, &["status", "--porcelain", "-uno"], None).unwrap();
+        assert!(status.lines().all(|line| !line.starts_with(" M")));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
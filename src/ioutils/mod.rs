@@ -1,4 +1,4 @@
+mod random;
 mod temp_file;
-pub use self::temp_file::next_random;
-pub use self::temp_file::temp_dir;
-pub use self::temp_file::temp_file;
+pub use self::random::random_hex;
+pub use self::temp_file::{temp_dir, temp_file, TempFile};
@@ -0,0 +1,33 @@
+//! Random names from the operating system's secure generator.
+
+use std::fmt::Write;
+use std::io::{Error, Result};
+
+/// Returns `bytes` random bytes as a lowercase hex string.
+pub fn random_hex(bytes: usize) -> Result<String> {
+    let mut buf = vec![0u8; bytes];
+    getrandom::fill(&mut buf).map_err(Error::other)?;
+
+    let mut hex = String::with_capacity(bytes * 2);
+    for byte in buf {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    Ok(hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::random_hex;
+
+    #[test]
+    fn random_hex_names() {
+        let a = random_hex(8).unwrap();
+        let b = random_hex(8).unwrap();
+
+        assert_eq!(a.len(), 16);
+        assert!(a
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+        assert_ne!(a, b);
+    }
+}
@@ -1,142 +1,202 @@
 //! Temporary file creation module.
 //!
 //! Exposes temp_file() which allows a user to
-//! create a temporary file using a custom prefix
-//! which is followed by a randomly generated
-//! 9-digit suffix.
+//! create a temporary file using a custom pattern,
+//! where a random string replaces the last `*`, Eg.
+//! `mmv-*.txt`, like Go's `os.CreateTemp()`.
+//!
+//! Names come from the operating system's secure
+//! generator, and files are created with `O_EXCL`,
+//! readable by their owner only. A `TempFile` is
+//! removed when dropped, including while unwinding a
+//! panic, or when SIGHUP, SIGINT or SIGTERM kills
+//! the process.
 
 use std::env;
-use std::fs::{create_dir, File, OpenOptions};
+use std::ffi::CString;
+use std::fs::{self, create_dir, File, OpenOptions};
 use std::io::{Error, ErrorKind, Result};
-use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
-use std::process;
-use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use lazy_static::lazy_static;
-
-// For this use case, there is no sharing among
-// threads - so ARC not used.
-lazy_static! {
-    static ref RAND: Mutex<u32> = Mutex::new(0);
-}
+use std::mem;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Once;
+
+use super::random_hex;
 
-pub fn reseed() -> u32 {
-    let start = SystemTime::now();
-    let since_the_epoch = start
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_nanos();
-    let pid = process::id();
+/// Number of attempts before giving up on finding a free name.
+const ATTEMPTS: usize = 10000;
 
-    (since_the_epoch + pid as u128) as u32
+/// Paths removed by the signal handler. A handler may run on any
+/// thread at any time, so paths are never freed once registered.
+const SLOTS: usize = 16;
+static SIGNAL_PATHS: [AtomicPtr<c_char>; SLOTS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; SLOTS];
+
+/// A temporary file, removed when dropped.
+#[derive(Debug)]
+pub struct TempFile {
+    file: File,
+    path: PathBuf,
+    /// Slot in `SIGNAL_PATHS`, if there was a free one.
+    slot: Option<usize>,
 }
 
-/// Returns a random 9-digit string.
-pub fn next_random() -> String {
-    let mut rand = RAND.lock().unwrap();
-    if *rand == 0 {
-        *rand = reseed();
+impl TempFile {
+    pub fn path(&self) -> &Path {
+        &self.path
     }
-    // Raise to u64 to avoid overflow issues
-    let r: u64 = (*rand as u64) * 1664525 + 1013904223; // constants from Numerical Recipes
-
-    // Reset rand so that each time this function is called,
-    // a new random value is generated.
-    *rand = 0;
 
-    // Lock will be unlocked when rand goes out of scope.
-    ((r as f64) % 1e9).to_string()
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
 }
 
-pub fn temp_file(dirname: &str, pattern: &str) -> Result<(File, String)> {
-    let mut dir = PathBuf::from(dirname);
-    if dirname.len() == 0 {
-        dir = env::temp_dir();
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            SIGNAL_PATHS[slot].store(ptr::null_mut(), Ordering::SeqCst);
+        }
+        // Nothing useful can be done if this fails.
+        fs::remove_file(&self.path).ok();
     }
+}
+
+/// Creates a new file in dirname, or the default temporary directory
+/// if it is empty, named after pattern.
+pub fn temp_file(dirname: &str, pattern: &str) -> Result<TempFile> {
+    let dir = if dirname.is_empty() {
+        env::temp_dir()
+    } else {
+        PathBuf::from(dirname)
+    };
+    let (prefix, suffix) = match pattern.rfind('*') {
+        Some(i) => (&pattern[..i], &pattern[i + 1..]),
+        None => (pattern, ""),
+    };
 
-    let mut n_conflict = 0;
-    let tmp: File;
-    for _i in 1..10000 {
-        let mut filename = "".to_owned();
-        filename.push_str(dir.to_str().unwrap());
-        filename.push_str(pattern);
-        // Append random suffix to pattern
-        filename.push_str(&next_random());
-
-        // Creates new file if it does not exist.
-        // Raises error if file already exists.
+    for _i in 0..ATTEMPTS {
+        let path = dir.join(format!("{}{}{}", prefix, random_hex(8)?, suffix));
+
+        // Raises error if file already exists, which is the only case
+        // worth another name.
         let result = OpenOptions::new()
             .write(true)
             .create_new(true)
-            .open(&filename);
-
-        if result.is_err() {
-            n_conflict += 1;
-            if n_conflict > 10 {
-                let mut rand = RAND.lock().unwrap();
-                *rand = reseed();
-                // Once out of this scope, lock should be released.
+            .mode(0o600)
+            .open(&path);
+
+        match result {
+            Ok(file) => {
+                let slot = watch(&path);
+                return Ok(TempFile { file, path, slot });
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(Error::new(
+                    err.kind(),
+                    format!("Error creating {}: {}", path.display(), err),
+                ))
             }
-        } else {
-            tmp = result?;
-            // Set read & write perms for owner of this file.
-            let metadata = tmp.metadata()?;
-            let mut permissions = metadata.permissions();
-            permissions.set_mode(0o600);
-            tmp.set_permissions(permissions)?;
-
-            return Ok((tmp, filename));
         }
     }
 
-    // raise IO error
-    Err(Error::new(ErrorKind::Other, "Failed to create temp file"))
+    Err(Error::new(
+        ErrorKind::AlreadyExists,
+        format!("No free temporary file name in {}", dir.display()),
+    ))
 }
 
-#[allow(dead_code)]
 pub fn temp_dir(dirname: &str, pattern: &str) -> Result<String> {
-    let mut dir = PathBuf::from(dirname);
-    if dirname.len() == 0 {
-        dir = env::temp_dir();
+    let dir = if dirname.is_empty() {
+        env::temp_dir()
+    } else {
+        PathBuf::from(dirname)
+    };
+
+    for _i in 0..ATTEMPTS {
+        let dirpath = dir.join(format!("{}{}", pattern, random_hex(8)?));
+        match create_dir(&dirpath) {
+            // Return string path for temporary directory.
+            Ok(()) => return Ok(String::from(dirpath.to_str().unwrap())),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(Error::other("Failed to create temp directory"))
+}
+
+/// Registers path for removal if the process is killed by a signal.
+fn watch(path: &Path) -> Option<usize> {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(install_handlers);
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?.into_raw();
+    for (slot, entry) in SIGNAL_PATHS.iter().enumerate() {
+        if entry
+            .compare_exchange(ptr::null_mut(), c_path, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            return Some(slot);
+        }
     }
 
-    let mut n_conflict = 0;
-    for _i in 1..10000 {
-        let rnd_dirname = format!("{}{}", pattern, next_random());
-        let mut dirpath = dir.clone();
-        dirpath.push(rnd_dirname);
-        // Failed to create directory.
-        if let Err(err) = create_dir(&dirpath) {
-            if err.kind() == ErrorKind::AlreadyExists {
-                n_conflict += 1;
-                if n_conflict > 10 {
-                    let mut rand = RAND.lock().unwrap();
-                    *rand = reseed();
-                    // Once out of this scope, lock should be released.
-                }
+    // SAFETY: c_path comes from into_raw() above and was never shared.
+    drop(unsafe { CString::from_raw(c_path) });
+    None
+}
+
+/// Handles signals which would kill the process, unless they are
+/// ignored or already handled.
+fn install_handlers() {
+    for &signal in &[libc::SIGHUP, libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: sigaction structs are plain data, and the handler
+        // only calls async-signal-safe functions.
+        unsafe {
+            let mut old: libc::sigaction = mem::zeroed();
+            if libc::sigaction(signal, ptr::null(), &mut old) != 0
+                || old.sa_sigaction != libc::SIG_DFL
+            {
                 continue;
-            } else {
-                break; // Go to return error
             }
+
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = remove_on_signal as extern "C" fn(c_int) as usize;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, ptr::null_mut());
         }
+    }
+}
 
-        // Success directory created, create_dir() returns ().
-        // Return string path for temporary directory.
-        return Ok(String::from(dirpath.to_str().unwrap()));
+extern "C" fn remove_on_signal(signal: c_int) {
+    for entry in &SIGNAL_PATHS {
+        let path = entry.swap(ptr::null_mut(), Ordering::SeqCst);
+        if !path.is_null() {
+            // SAFETY: registered paths are valid and never freed.
+            unsafe { libc::unlink(path) };
+        }
     }
 
-    Err(Error::new(
-        ErrorKind::Other,
-        "Failed to create temp directory",
-    ))
+    // Die of the signal, as if it had not been handled.
+    // SAFETY: both functions are async-signal-safe.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::temp_dir;
+    use super::{temp_dir, temp_file};
     use std::fs;
+    use std::io::{ErrorKind, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::panic;
+    use std::path::Path;
 
     #[test]
     fn create_temp_dir() {
@@ -148,4 +208,65 @@ mod tests {
         // Clean up
         assert!(fs::remove_dir(dirpath).is_ok());
     }
+
+    #[test]
+    fn create_temp_file() {
+        let dir = temp_dir("", "mmv-temp-file-").unwrap();
+
+        let mut tmp = temp_file(&dir, "mmv-*.txt").unwrap();
+        let path = tmp.path().to_path_buf();
+        tmp.file().write_all(b"a\n").unwrap();
+
+        assert_eq!(path.parent(), Some(Path::new(&dir)));
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(
+            name.starts_with("mmv-") && name.ends_with(".txt"),
+            "{}",
+            name
+        );
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a\n");
+        assert_ne!(temp_file(&dir, "mmv-*.txt").unwrap().path(), path);
+
+        drop(tmp);
+        assert!(!path.exists());
+
+        // Without `*`, the random string is appended.
+        let tmp = temp_file(&dir, "mmv-").unwrap();
+        assert!(tmp
+            .path()
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("mmv-"));
+        drop(tmp);
+
+        fs::remove_dir(dir).unwrap();
+    }
+
+    #[test]
+    fn temp_file_errors() {
+        let err = temp_file("/nonexistent-mmv-dir", "mmv-*.txt").unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(err
+            .to_string()
+            .starts_with("Error creating /nonexistent-mmv-dir/mmv-"));
+    }
+
+    #[test]
+    fn remove_on_panic() {
+        let dir = temp_dir("", "mmv-temp-file-").unwrap();
+
+        let result = panic::catch_unwind(|| {
+            let tmp = temp_file(&dir, "mmv-*.txt").unwrap();
+            panic!("{}", tmp.path().display());
+        });
+
+        assert!(result.is_err());
+        assert!(fs::read_dir(&dir).unwrap().next().is_none());
+        fs::remove_dir(dir).unwrap();
+    }
 }
@@ -0,0 +1,26 @@
+//! Core of mmv: planning and executing renames, plus everything the
+//! command line tool builds on. Split from the binary so benchmarks
+//! and fuzz targets can call the planner directly.
+//!
+//! Only unix is supported: the trash, preflight checks and shell
+//! scripts are built on POSIX APIs and semantics.
+
+#[cfg(not(unix))]
+compile_error!("mmv only supports unix systems");
+
+pub mod buffer;
+pub mod editor;
+pub mod filepath;
+pub mod filesystem;
+pub mod git;
+pub mod ioutils;
+pub mod lock;
+pub mod mmv;
+pub mod planfile;
+pub mod preflight;
+pub mod preview;
+pub mod references;
+pub mod report;
+pub mod script;
+pub mod snapshot;
+pub mod trash;
@@ -0,0 +1,141 @@
+//! Advisory locks which keep two mmv sessions from working on the
+//! same directories at once.
+//!
+//! Each affected parent directory is locked with `flock()` for as
+//! long as the returned `DirLocks` lives, covering both planning
+//! (while the editor is open) and execution. Locks are released
+//! when their file descriptors are closed, so a crashed session
+//! never leaves a stale lock behind.
+
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::filepath::clean;
+
+/// How often a held lock is retried while waiting.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default)]
+pub struct DirLocks {
+    // Canonical directory paths and their locked descriptors.
+    held: Vec<(PathBuf, File)>,
+}
+
+impl DirLocks {
+    pub fn new() -> Self {
+        DirLocks::default()
+    }
+
+    /// Locks the directories containing each of `paths`, waiting up
+    /// to `timeout` for other sessions to release them.
+    pub fn lock<I, P>(&mut self, paths: I, timeout: Duration) -> Result<(), String>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        // Lock in a fixed order so that two sessions never wait on
+        // each other while each holding part of the set.
+        let mut dirs = BTreeSet::<PathBuf>::new();
+        for path in paths {
+            let dir = existing_parent(&clean(path));
+            if let Ok(dir) = fs::canonicalize(dir) {
+                dirs.insert(dir);
+            }
+        }
+
+        for dir in dirs {
+            // flock() conflicts between descriptors of the same process
+            // too, so never lock a directory twice.
+            if self.held.iter().any(|(held, _)| *held == dir) {
+                continue;
+            }
+
+            let file = lock_dir(&dir, timeout)?;
+            self.held.push((dir, file));
+        }
+
+        Ok(())
+    }
+}
+
+fn lock_dir(dir: &Path, timeout: Duration) -> Result<File, String> {
+    let file = File::open(dir).map_err(|err| format!("Cannot lock {}:\n{}", dir.display(), err))?;
+    let start = Instant::now();
+
+    loop {
+        // SAFETY: the descriptor stays open for the duration of the call.
+        let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if res == 0 {
+            return Ok(file);
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::WouldBlock {
+            return Err(format!("Cannot lock {}:\n{}", dir.display(), err));
+        }
+
+        if start.elapsed() >= timeout {
+            return Err(format!(
+                "Another mmv session is working in {}. \
+                 Wait for it to finish or use --lock-timeout.",
+                dir.display()
+            ));
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// Returns the closest directory containing `path` which exists, since
+/// destination directories may only be created later.
+fn existing_parent(path: &Path) -> PathBuf {
+    let mut dir = path.to_path_buf();
+    while dir.pop() {
+        if dir.as_os_str().is_empty() {
+            break;
+        }
+        if fs::metadata(&dir).is_ok() {
+            return dir;
+        }
+    }
+
+    PathBuf::from(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use super::super::ioutils::temp_dir;
+    use super::DirLocks;
+
+    #[test]
+    fn lock_directories_once() {
+        let dir = PathBuf::from(temp_dir("", "mmv-lock-").unwrap());
+        let paths = [dir.join("foo"), dir.join("bar"), dir.join("x/y/z")];
+
+        let mut locks = DirLocks::new();
+        assert!(locks.lock(&paths, Duration::from_secs(0)).is_ok());
+        // Already held by this session.
+        assert!(locks.lock(&paths, Duration::from_secs(0)).is_ok());
+        assert_eq!(locks.held.len(), 1);
+
+        // Another session has to wait and gives up after the timeout.
+        let mut other = DirLocks::new();
+        let start = Instant::now();
+        let res = other.lock(&paths, Duration::from_millis(200));
+        assert!(res.unwrap_err().starts_with("Another mmv session"));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        drop(locks);
+        assert!(other.lock(&paths, Duration::from_secs(0)).is_ok());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
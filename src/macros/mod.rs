@@ -1,3 +0,0 @@
-//! Custom macros
-
-mod defer;
@@ -1,125 +1,783 @@
 extern crate clap;
 
-mod filepath;
-mod ioutils;
-mod macros;
-mod mmv;
-
 // Default modules
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::env;
-use std::fs::{read_to_string, remove_file};
+use std::fs::{self, create_dir_all, read_to_string};
 use std::io::Write;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::str;
+use std::time::{Duration, SystemTime};
 
 // External modules
-use clap::{App, Arg, Values};
+use clap::{App, AppSettings, Arg, Values};
+
+use ::mmv::mmv;
+use ::mmv::{
+    buffer, editor, filepath, git, ioutils, lock, planfile, preflight, preview, references, report,
+    script, snapshot, trash,
+};
 
-static APP_NAME: &'static str = "mmv";
+static APP_NAME: &str = "mmv";
+
+/// Options collected from the command line.
+#[derive(Clone)]
+pub struct Config {
+    /// Root directory to search for references to renamed paths.
+    pub references_root: Option<PathBuf>,
+    /// Update the git index like `git mv` does.
+    pub git: bool,
+    pub mode: mmv::Mode,
+    /// How long to wait for another mmv session to finish.
+    pub lock_timeout: Duration,
+    /// Flush renames to disk before exiting.
+    pub durable: bool,
+    /// Number of threads renaming independent chains.
+    pub jobs: usize,
+    /// How renames are executed.
+    pub backend: mmv::Backend,
+    /// What to do with destinations which already exist.
+    pub conflict: mmv::Conflict,
+    /// Do not ask for confirmation.
+    pub yes: bool,
+    /// Where to write a JSON report of the run, `-` for stdout.
+    pub report_file: Option<PathBuf>,
+    /// Write a shell script performing the renames instead of
+    /// renaming, `-` for stdout.
+    pub emit_sh: Option<PathBuf>,
+    /// Write a shell script undoing the renames, `-` for stdout.
+    pub emit_undo_sh: Option<PathBuf>,
+    /// Simulate renames in memory instead of renaming.
+    pub dry_run: bool,
+    /// A report or script is written to stdout, so messages for the
+    /// user go to stderr.
+    pub messages_to_stderr: bool,
+}
 
 fn main() -> Result<(), String> {
     let file_args = Arg::new("files")
         .about("Files to rename")
         .required(true)
         .multiple(true);
+    let update_references_arg = Arg::new("update-references")
+        .long("update-references")
+        .about("Rewrite references to renamed paths in text files")
+        .global(true);
+    let references_root_arg = Arg::new("references-root")
+        .long("references-root")
+        .about("Directory searched by --update-references (default: current directory)")
+        .takes_value(true)
+        .requires("update-references")
+        .global(true);
+    let git_arg = Arg::new("git")
+        .long("git")
+        .about("Update the git index for renamed tracked paths, like `git mv`")
+        .global(true);
+    let copy_arg = Arg::new("copy")
+        .long("copy")
+        .about("Copy files instead of moving them")
+        .conflicts_with_all(&["link", "symlink", "git", "update-references"])
+        .global(true);
+    let link_arg = Arg::new("link")
+        .long("link")
+        .about("Hard link files instead of moving them")
+        .conflicts_with_all(&["copy", "symlink", "git", "update-references"])
+        .global(true);
+    let symlink_arg = Arg::new("symlink")
+        .long("symlink")
+        .about("Symbolic link files instead of moving them")
+        .conflicts_with_all(&["copy", "link", "git", "update-references"])
+        .global(true);
+    let on_conflict_arg = Arg::new("on-conflict")
+        .long("on-conflict")
+        .about("What to do when a destination already exists")
+        .takes_value(true)
+        .value_name("POLICY")
+        .possible_values(&mmv::Conflict::NAMES)
+        .default_value("refuse")
+        .global(true);
+    let yes_arg = Arg::new("yes")
+        .short('y')
+        .long("yes")
+        .about("Do not ask for confirmation before renaming")
+        .global(true);
+    let report_arg = Arg::new("report")
+        .long("report")
+        .about("Print a report of executed renames")
+        .takes_value(true)
+        .value_name("FORMAT")
+        .possible_values(&["json"])
+        .global(true);
+    let report_file_arg = Arg::new("report-file")
+        .long("report-file")
+        .about("Write the report to a file instead of stdout")
+        .takes_value(true)
+        .value_name("PATH")
+        .requires("report")
+        .global(true);
+    let emit_sh_arg = Arg::new("emit-sh")
+        .long("emit-sh")
+        .about("Write a shell script performing the renames instead of renaming (- for stdout)")
+        .takes_value(true)
+        .value_name("FILE")
+        .conflicts_with_all(&["copy", "link", "symlink", "git", "update-references"])
+        .global(true);
+    let emit_undo_sh_arg = Arg::new("emit-undo-sh")
+        .long("emit-undo-sh")
+        .about("Write a shell script undoing the renames (- for stdout)")
+        .takes_value(true)
+        .value_name("FILE")
+        .conflicts_with_all(&["copy", "link", "symlink", "git", "update-references"])
+        .global(true);
+    let dry_run_arg = Arg::new("dry-run")
+        .short('n')
+        .long("dry-run")
+        .about("Simulate the renames in memory and report any that would fail")
+        .conflicts_with_all(&[
+            "copy",
+            "link",
+            "symlink",
+            "emit-sh",
+            "emit-undo-sh",
+            "report",
+        ])
+        .global(true);
+    let durable_arg = Arg::new("durable")
+        .long("durable")
+        .about("Sync affected directories to disk so renames survive a crash")
+        .global(true);
+    let jobs_arg = Arg::new("jobs")
+        .short('j')
+        .long("jobs")
+        .about("Rename independent chains on up to N threads")
+        .takes_value(true)
+        .value_name("N")
+        .global(true);
+    let backend_arg = Arg::new("backend")
+        .long("backend")
+        .about("How renames are executed (io-uring falls back to std if unsupported)")
+        .takes_value(true)
+        .value_name("BACKEND")
+        .possible_values(&["std", "io-uring"])
+        .global(true);
+    let lock_timeout_arg = Arg::new("lock-timeout")
+        .long("lock-timeout")
+        .about("Seconds to wait for another mmv session using the same directories")
+        .takes_value(true)
+        .value_name("SECONDS")
+        .global(true);
+
+    let output_arg = Arg::new("output")
+        .short('o')
+        .long("output")
+        .about("File to write the plan to")
+        .takes_value(true)
+        .value_name("PLAN")
+        .required(true);
+    let plan_file_arg = Arg::new("plan")
+        .about("Plan written by `mmv plan`")
+        .required(true);
+    let plan_cmd = App::new("plan")
+        .about("Edit and validate renames, then save them for `mmv apply`")
+        .arg(&file_args)
+        .arg(&output_arg);
+    let apply_cmd = App::new("apply")
+        .about("Execute a plan saved by `mmv plan`")
+        .arg(&plan_file_arg);
 
     let matches = App::new("Rust mmv")
         .version("1.0")
         .about("Performs `mv` on multiple paths.")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(plan_cmd)
+        .subcommand(apply_cmd)
         .arg(&file_args)
+        .arg(&update_references_arg)
+        .arg(&references_root_arg)
+        .arg(&git_arg)
+        .arg(&copy_arg)
+        .arg(&link_arg)
+        .arg(&symlink_arg)
+        .arg(&lock_timeout_arg)
+        .arg(&durable_arg)
+        .arg(&jobs_arg)
+        .arg(&backend_arg)
+        .arg(&on_conflict_arg)
+        .arg(&yes_arg)
+        .arg(&report_arg)
+        .arg(&report_file_arg)
+        .arg(&emit_sh_arg)
+        .arg(&emit_undo_sh_arg)
+        .arg(&dry_run_arg)
         .get_matches();
 
-    let file_inputs: Option<Values> = matches.values_of(file_args.get_name());
-    if let Some(file_values) = file_inputs {
-        // Values is an iterator and will be consumed immediately
-        // in any loop. So create a vector of paths for multiple
-        // borrows.
-        let files = file_values.into_iter().collect::<Vec<&str>>();
-        run(files).unwrap_or_else(|msg| {
-            eprintln!("{}", msg);
-        });
+    // Options are global, so subcommands have their own copy.
+    let opts = matches.subcommand().map_or(&matches, |(_, sub)| sub);
+
+    let mut config = Config {
+        references_root: None,
+        git: opts.is_present(git_arg.get_name()),
+        mode: default_mode(),
+        lock_timeout: Duration::from_secs(0),
+        durable: opts.is_present(durable_arg.get_name()),
+        jobs: 1,
+        backend: mmv::Backend::Std,
+        conflict: mmv::Conflict::Refuse,
+        yes: opts.is_present(yes_arg.get_name()),
+        report_file: None,
+        emit_sh: opts.value_of(emit_sh_arg.get_name()).map(PathBuf::from),
+        emit_undo_sh: opts
+            .value_of(emit_undo_sh_arg.get_name())
+            .map(PathBuf::from),
+        dry_run: opts.is_present(dry_run_arg.get_name()),
+        messages_to_stderr: false,
+    };
+    if opts.is_present(copy_arg.get_name()) {
+        config.mode = mmv::Mode::Copy;
+    } else if opts.is_present(link_arg.get_name()) {
+        config.mode = mmv::Mode::Link;
+    } else if opts.is_present(symlink_arg.get_name()) {
+        config.mode = mmv::Mode::Symlink;
+    }
+    if let Some(policy) = opts.value_of(on_conflict_arg.get_name()) {
+        config.conflict = policy.parse()?;
+    }
+    if config.mode != mmv::Mode::Move
+        && matches!(
+            config.conflict,
+            mmv::Conflict::Overwrite | mmv::Conflict::Backup | mmv::Conflict::NumberedBackup
+        )
+    {
+        return Err(String::from(
+            "Only refuse, skip and suffix conflict policies are supported when copying or linking",
+        ));
+    }
+    if config.emit_sh.is_some() && config.conflict == mmv::Conflict::Overwrite {
+        return Err(String::from(
+            "--emit-sh cannot overwrite destinations, since scripts refuse existing ones",
+        ));
+    }
+    if let Some(secs) = opts.value_of(lock_timeout_arg.get_name()) {
+        match secs.parse::<f64>() {
+            Ok(secs) if secs >= 0.0 => config.lock_timeout = Duration::from_secs_f64(secs),
+            _ => return Err(format!("Invalid lock timeout {}", secs)),
+        }
+    }
+    if let Some(jobs) = opts.value_of(jobs_arg.get_name()) {
+        match jobs.parse::<usize>() {
+            Ok(jobs) if jobs >= 1 => config.jobs = jobs,
+            _ => return Err(format!("Invalid number of jobs {}", jobs)),
+        }
+    }
+    if let Some(backend) = opts.value_of(backend_arg.get_name()) {
+        config.backend = backend.parse()?;
+    }
+    if opts.is_present(report_arg.get_name()) {
+        let path = opts.value_of(report_file_arg.get_name()).unwrap_or("-");
+        config.report_file = Some(PathBuf::from(path));
+    }
+    let stdout = Path::new("-");
+    config.messages_to_stderr = [&config.report_file, &config.emit_sh, &config.emit_undo_sh]
+        .iter()
+        .any(|path| path.as_deref() == Some(stdout));
+    if opts.is_present(update_references_arg.get_name()) {
+        let root = matches
+            .value_of(references_root_arg.get_name())
+            .unwrap_or(".");
+        config.references_root = Some(PathBuf::from(root));
+    }
+
+    let result = match matches.subcommand() {
+        Some(("plan", sub)) => {
+            let files = sub
+                .values_of(file_args.get_name())
+                .map(|values| values.collect::<Vec<&str>>())
+                .unwrap_or_default();
+            let output = sub.value_of(output_arg.get_name()).unwrap_or_default();
+            save_plan(files, Path::new(output), &config)
+        }
+        Some(("apply", sub)) => {
+            let path = sub.value_of(plan_file_arg.get_name()).unwrap_or_default();
+            apply_plan(Path::new(path), &config)
+        }
+        _ => {
+            let file_inputs: Option<Values> = matches.values_of(file_args.get_name());
+            // Values is an iterator and will be consumed immediately
+            // in any loop. So create a vector of paths for multiple
+            // borrows.
+            let files = file_inputs
+                .map(|values| values.collect::<Vec<&str>>())
+                .unwrap_or_default();
+            run(files, &config)
+        }
+    };
+    if let Err(msg) = result {
+        eprintln!("{}", msg);
+        process::exit(1);
     }
 
     Ok(())
 }
 
-pub fn run(files: Vec<&str>) -> Result<(), String> {
-    // Check for duplicate paths
-    let mut check_paths = HashSet::<&str>::new();
-    for file in &files {
-        let res = check_paths.insert(file);
-        if !res {
-            return Err(format!("Duplicate source {}", file));
+/// Like classic mmv, behave as `mcp` or `mln` when installed under
+/// those names.
+fn default_mode() -> mmv::Mode {
+    let program = env::args().next().unwrap_or_default();
+    match Path::new(&program)
+        .file_name()
+        .and_then(|name| name.to_str())
+    {
+        Some("mcp") => mmv::Mode::Copy,
+        Some("mln") => mmv::Mode::Link,
+        _ => mmv::Mode::Move,
+    }
+}
+
+pub fn run(files: Vec<&str>, config: &Config) -> Result<(), String> {
+    // Keep other mmv sessions out of the affected directories until
+    // the run is over.
+    let mut locks = lock::DirLocks::new();
+    let mut snapshot = snapshot::Snapshot::new();
+
+    // Ask for confirmation unless told otherwise, or there is
+    // nobody to answer.
+    let prompt = !config.yes && !config.dry_run && preview::can_prompt();
+    let plan = match edit_plan(&files, config, &mut locks, &mut snapshot, prompt)? {
+        Some(plan) => plan,
+        None => {
+            message(config, "Nothing to do.\n");
+            return Ok(());
         }
+    };
+
+    check_unchanged(&snapshot, "before renaming")?;
+    finish(&plan, config)
+}
+
+/// Same as `run()` but saves the plan to `output` instead of
+/// executing it.
+fn save_plan(files: Vec<&str>, output: &Path, config: &Config) -> Result<(), String> {
+    let mut locks = lock::DirLocks::new();
+    let mut snapshot = snapshot::Snapshot::new();
+    let plan = match edit_plan(&files, config, &mut locks, &mut snapshot, false)? {
+        Some(plan) => plan,
+        None => {
+            message(config, "Nothing to do, no plan saved.\n");
+            return Ok(());
+        }
+    };
+    check_unchanged(&snapshot, "while planning")?;
+
+    planfile::PlanFile::new(
+        config.mode,
+        &plan.renames,
+        &plan.backups,
+        &plan.trash,
+        &plan.create,
+        snapshot,
+    )?
+    .write(output)?;
+    message(config, &format!("Plan saved to {}\n", output.display()));
+
+    Ok(())
+}
+
+/// Executes a plan saved by `save_plan()` if none of its files changed
+/// since.
+fn apply_plan(path: &Path, config: &Config) -> Result<(), String> {
+    let saved = planfile::PlanFile::read(path)?;
+
+    // The plan was validated for its own mode.
+    let mut config = config.clone();
+    config.mode = saved.mode;
+    if config.mode != mmv::Mode::Move && (config.git || config.references_root.is_some()) {
+        return Err(String::from(
+            "--git and --update-references cannot be used with a copy or link plan",
+        ));
     }
 
-    // Create temporary file
-    let tmp_filename_prefix = format!("{}{}", APP_NAME, "-");
-    let (mut tmp, tmp_file_path) = ioutils::temp_file("", &tmp_filename_prefix).unwrap();
-    // Remove tmp file after run().
-    defer!(remove_file(&tmp_file_path).unwrap_or_else(|msg| {
-        eprintln!("Error removing tmp file:\n{}", msg);
-    }));
-    for path in &files {
-        let path_with_newline = format!("{}\n", path);
-        tmp.write(path_with_newline.as_bytes()).unwrap();
+    let mut locks = lock::DirLocks::new();
+    locks.lock(saved.fingerprints.paths(), config.lock_timeout)?;
+    check_unchanged(&saved.fingerprints, "since planning")?;
+
+    let resolved = mmv::Resolved {
+        renames: saved.rename_map(),
+        backups: saved.backups.clone(),
+    };
+    let plan = complete_plan(resolved, saved.trash.clone(), saved.create.clone(), &config)?;
+
+    if !config.yes && !config.dry_run && preview::can_prompt() {
+        let renames = plan.renames.iter().collect::<Vec<(&PathBuf, &PathBuf)>>();
+        let color = preview::use_color(config.messages_to_stderr);
+        message(
+            &config,
+            &preview::summary(&renames, &plan.trash, &plan.create, color),
+        );
+        // A saved plan cannot be edited, edit means no as well.
+        if preview::confirm()? != preview::Answer::Yes {
+            return Err(String::from("Aborted, nothing was renamed."));
+        }
     }
 
-    // Read EDITOR env
-    let default_editor = String::from("vi");
-    let mut editor = env::var("EDITOR").unwrap_or(default_editor.to_owned());
-    if editor.len() == 0 {
-        editor = default_editor;
+    check_unchanged(&saved.fingerprints, "before renaming")?;
+    finish(&plan, &config)
+}
+
+/// Lets the user edit files until the result is valid (and confirmed
+/// if `prompt` is set). Affected directories are locked and every
+/// path involved is recorded in snapshot. Returns None if the edits
+/// change nothing.
+fn edit_plan(
+    files: &[&str],
+    config: &Config,
+    locks: &mut lock::DirLocks,
+    snapshot: &mut snapshot::Snapshot,
+    prompt: bool,
+) -> Result<Option<Plan>, String> {
+    // Check for duplicate paths, Eg. foo and ./foo
+    let mut check_paths = HashSet::<PathBuf>::new();
+    let mut duplicates = BTreeSet::<PathBuf>::new();
+    for file in files {
+        let path = filepath::clean(file);
+        if !check_paths.insert(path.clone()) {
+            duplicates.insert(path);
+        }
+    }
+    if !duplicates.is_empty() {
+        return Err(duplicates
+            .iter()
+            .map(|path| format!("Duplicate source {}", path.display()))
+            .collect::<Vec<String>>()
+            .join("\n"));
     }
 
-    // Separate editor command from its args.
-    let fields: Vec<&str> = editor.splitn(2, " ").collect();
-    let mut args = Vec::<&str>::new();
-    if fields.len() > 1 {
-        args = fields[1].split_whitespace().collect();
+    locks.lock(files, config.lock_timeout)?;
+
+    // Remember the state of every source, as other processes may
+    // change them while the editor is open.
+    for file in files {
+        snapshot.add(file);
     }
-    args.push(&tmp_file_path);
 
-    // Create and execute command.
-    if let Err(cmd_err) = Command::new(fields[0]) // First item is editor command
-        .args(args)
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        // executes command
-        .output()
-    {
-        // Executing command has errors.
-        return Err(format!("Error executing command:\n{}", cmd_err.to_string()));
+    // Create temporary file, removed once editing is done. The .txt
+    // suffix lets editors pick plain text.
+    let tmp_pattern = format!("{}-*.txt", APP_NAME);
+    let mut tmp = ioutils::temp_file("", &tmp_pattern)
+        .map_err(|err| format!("Error creating temporary file:\n{}", err))?;
+    tmp.file()
+        .write_all(buffer::format(files).as_bytes())
+        .map_err(|err| format!("Error writing {}:\n{}", tmp.path().display(), err))?;
+
+    loop {
+        editor::edit(tmp.path())
+            .map_err(|err| format!("{}\nAborted, nothing was renamed.", err))?;
+
+        // Read destination paths from tmp file.
+        // (Happens after user updates paths with editor)
+        let contents = read_to_string(tmp.path())
+            .map_err(|err| format!("Error reading {}:\n{}", tmp.path().display(), err))?;
+        if buffer::is_blank(&contents) {
+            return Err(String::from("Empty buffer, aborted, nothing was renamed."));
+        }
+        let edits = buffer::parse(&contents, files)?;
+        if edits.is_empty() {
+            return Ok(None);
+        }
+
+        locks.lock(
+            edits.renames.values().chain(edits.create.iter()),
+            config.lock_timeout,
+        )?;
+        check_unchanged(snapshot, "while editing")?;
+
+        let plan = build_plan(edits, config)?;
+        for dst in plan.renames.values().chain(plan.create.iter()) {
+            snapshot.add(dst);
+        }
+
+        if !prompt {
+            return Ok(Some(plan));
+        }
+
+        let renames = plan.renames.iter().collect::<Vec<(&PathBuf, &PathBuf)>>();
+        let color = preview::use_color(config.messages_to_stderr);
+        message(
+            config,
+            &preview::summary(&renames, &plan.trash, &plan.create, color),
+        );
+        match preview::confirm()? {
+            preview::Answer::Yes => return Ok(Some(plan)),
+            preview::Answer::No => return Err(String::from("Aborted, nothing was renamed.")),
+            preview::Answer::Edit => continue,
+        }
+    }
+}
+
+/// Writes the requested shell scripts, then executes plan unless a
+/// script performing it was written instead, or it is a dry run.
+fn finish(plan: &Plan, config: &Config) -> Result<(), String> {
+    if config.dry_run {
+        let edges = mmv::dry_run(
+            &plan.renames,
+            &plan.trash,
+            &plan.create,
+            config.conflict == mmv::Conflict::Overwrite,
+        )
+        .map_err(|err| format!("Dry run failed, nothing was renamed:\n{}", err))?;
+        for edge in &edges {
+            message(
+                config,
+                &format!("{} -> {}\n", edge.src.display(), edge.dst.display()),
+            );
+        }
+        message(
+            config,
+            &format!(
+                "Dry run: {} renames would succeed, nothing was renamed.\n",
+                edges.len()
+            ),
+        );
+        return Ok(());
     }
 
-    // Read destination paths from tmp file.
-    // (Happens after user updates paths with editor)
-    //
-    // PathBuf is used to pass ownership from main() into rename().
-    // After this the paths data is no longer needed.
-    let mut src_to_dst_map = HashMap::<PathBuf, PathBuf>::new();
-    let contents = read_to_string(&tmp_file_path).unwrap();
-    let edited_lines: Vec<&str> = contents
-        .trim_end_matches("\n")
-        .split_terminator("\n") // Normal split can result in [""]
-        .filter(|path| (*path).replace(" ", "").len() > 0) // Clear empty paths
-        .collect();
+    if config.emit_sh.is_some() || config.emit_undo_sh.is_some() {
+        if config.mode != mmv::Mode::Move {
+            return Err(String::from(
+                "Shell scripts can only be written when moving files",
+            ));
+        }
+        if !plan.trash.is_empty() {
+            return Err(String::from("Shell scripts cannot move files to the trash"));
+        }
 
-    // Raise error when user add/deletes a line from tmp file.
-    if edited_lines.len() != files.len() {
-        return Err(String::from("Do not add or delete lines."));
+        let edges = mmv::edges(&plan.renames)?;
+        if let Some(path) = &config.emit_undo_sh {
+            script::write(path, &script::undo_script(&edges))?;
+        }
+        if let Some(path) = &config.emit_sh {
+            return script::write(path, &script::rename_script(&edges, &plan.create));
+        }
     }
 
-    edited_lines
-        .iter()
-        .zip(files.into_iter())
-        .for_each(|(dst, src)| {
-            src_to_dst_map.insert(PathBuf::from(src), PathBuf::from(dst));
-        });
-    mmv::rename(&src_to_dst_map, None)?;
+    execute_reported(plan, config)
+}
+
+/// Executes plan and writes the report if one was requested.
+fn execute_reported(plan: &Plan, config: &Config) -> Result<(), String> {
+    let started = SystemTime::now();
+    let mut log = mmv::Log::default();
+    let result = execute(plan, config, &mut log);
+    if let Some(path) = &config.report_file {
+        report::Report::new(
+            &plan.renames,
+            &plan.backups,
+            &plan.trash,
+            &plan.create,
+            &log,
+            started,
+            &result,
+        )
+        .write(path)?;
+    }
+
+    result
+}
+
+/// Everything that will be done, computed before touching any file.
+struct Plan {
+    renames: HashMap<PathBuf, PathBuf>,
+    /// Existing destinations which `renames` moves to a backup name.
+    backups: Vec<PathBuf>,
+    trash: Vec<PathBuf>,
+    create: Vec<PathBuf>,
+    rewrites: Vec<references::Rewrite>,
+    index_update: Option<git::IndexUpdate>,
+}
+
+/// Validates the edits and works out everything needed to execute them.
+fn build_plan(edits: buffer::Edits, config: &Config) -> Result<Plan, String> {
+    // Settle destinations which already exist, so that every later
+    // step works with the final names.
+    let resolved = mmv::resolve_conflicts(
+        &edits.renames,
+        &edits.trash,
+        config.mode != mmv::Mode::Move,
+        config.conflict,
+    )?;
+
+    complete_plan(resolved, edits.trash, edits.create, config)
+}
+
+/// Works out the rest of the plan for renames whose conflicts are
+/// already settled.
+fn complete_plan(
+    resolved: mmv::Resolved,
+    trash: Vec<PathBuf>,
+    create: Vec<PathBuf>,
+    config: &Config,
+) -> Result<Plan, String> {
+    let mmv::Resolved { renames, backups } = resolved;
+
+    // Plan reference rewrites before any path changes.
+    let mut rewrites = Vec::<references::Rewrite>::new();
+    if let Some(root) = &config.references_root {
+        rewrites = references::plan(root, &renames)
+            .map_err(|err| format!("Error searching references:\n{}", err))?;
+        message(config, &references::preview(&rewrites));
+    }
+
+    let mut index_update = None;
+    if config.git {
+        index_update = Some(git::plan(&renames)?);
+    }
+
+    let problems = preflight::check(&renames, &trash, &create, config.mode != mmv::Mode::Move);
+    if !problems.is_empty() {
+        return Err(problems.join("\n"));
+    }
+
+    Ok(Plan {
+        renames,
+        backups,
+        trash,
+        create,
+        rewrites,
+        index_update,
+    })
+}
+
+fn execute(plan: &Plan, config: &Config, log: &mut mmv::Log) -> Result<(), String> {
+    // Trash first, so trashed paths can be reused as destinations.
+    let mut trashed = Vec::<trash::Trashed>::new();
+    for path in &plan.trash {
+        match trash::trash(path) {
+            Ok(item) => trashed.push(item),
+            Err(err) => {
+                restore_trashed(&trashed);
+                return Err(format!(
+                    "Error moving {} to trash:\n{}",
+                    path.display(),
+                    err
+                ));
+            }
+        }
+    }
+
+    let options = mmv::Options {
+        mode: config.mode,
+        durable: config.durable,
+        jobs: config.jobs,
+        backend: config.backend,
+    };
+    if let Err(err) = mmv::rename_logged(&plan.renames, None, &options, log) {
+        restore_trashed(&trashed);
+        return Err(err);
+    }
+
+    let mut created = Vec::<PathBuf>::new();
+    if let Err(err) = after_rename(plan, log, &mut created) {
+        for dir in created.iter().rev() {
+            if let Err(err) = fs::remove_dir(dir) {
+                eprintln!("Error removing {}:\n{}", dir.display(), err);
+            }
+        }
+        if let Err(err) = mmv::undo(log, config.mode) {
+            eprintln!("{}", err);
+        }
+        restore_trashed(&trashed);
+        return Err(err);
+    }
+
+    if config.durable {
+        sync_dirs(&trashed, &created)?;
+    }
+
+    Ok(())
+}
+
+/// Flushes the directories changed by trashing files and creating
+/// directories to disk. Renamed paths are synced by mmv.
+fn sync_dirs(trashed: &[trash::Trashed], created: &[PathBuf]) -> Result<(), String> {
+    let mut dirs = BTreeSet::<PathBuf>::new();
+    for item in trashed {
+        dirs.extend(item.original.parent().map(Path::to_path_buf));
+        // The trash directories themselves may have been created.
+        dirs.extend(item.file.ancestors().skip(1).map(Path::to_path_buf));
+        dirs.extend(item.info.ancestors().skip(1).map(Path::to_path_buf));
+    }
+    dirs.extend(
+        created
+            .iter()
+            .filter_map(|dir| dir.parent().map(Path::to_path_buf)),
+    );
+
+    mmv::sync_dirs(dirs).map_err(|err| format!("Error syncing directories:\n{}", err))
+}
+
+/// Runs the steps which follow renaming. Directories which did not
+/// exist are added to `created`, outermost first. On failure, the
+/// references already rewritten are restored.
+fn after_rename(plan: &Plan, log: &mut mmv::Log, created: &mut Vec<PathBuf>) -> Result<(), String> {
+    for dir in &plan.create {
+        let missing = dir
+            .ancestors()
+            .take_while(|path| !path.as_os_str().is_empty() && fs::symlink_metadata(path).is_err())
+            .map(Path::to_path_buf)
+            .collect::<Vec<PathBuf>>();
+        let result = create_dir_all(dir);
+        // Some ancestors may exist even if creating dir failed.
+        created.extend(missing.into_iter().rev().filter(|path| path.is_dir()));
+        result.map_err(|err| format!("Error creating {}:\n{}", dir.display(), err))?;
+    }
+
+    references::apply(&plan.rewrites, log)
+        .map_err(|err| format!("Error updating references:\n{}", err))?;
+
+    // Last, since the index cannot be restored.
+    if let Some(update) = &plan.index_update {
+        if let Err(err) = git::apply(update) {
+            references::undo(&plan.rewrites, log);
+            return Err(format!("Error updating the git index:\n{}", err));
+        }
+    }
 
     Ok(())
 }
+
+/// Prints text for the user, to stderr when stdout carries a report
+/// or script.
+fn message(config: &Config, text: &str) {
+    if config.messages_to_stderr {
+        eprint!("{}", text);
+    } else {
+        print!("{}", text);
+    }
+}
+
+/// Aborts if any path in snapshot was changed by another process.
+fn check_unchanged(snapshot: &snapshot::Snapshot, when: &str) -> Result<(), String> {
+    let changes = snapshot.changes();
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    Err(format!(
+        "Files changed {}, nothing was renamed:\n{}",
+        when,
+        changes.join("\n")
+    ))
+}
+
+/// Moves trashed files back in reverse order.
+fn restore_trashed(trashed: &[trash::Trashed]) {
+    for item in trashed.iter().rev() {
+        if let Err(err) = trash::restore(item) {
+            eprintln!(
+                "Error restoring {} from trash:\n{}",
+                item.original.display(),
+                err
+            );
+        }
+    }
+}
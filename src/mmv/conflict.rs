@@ -0,0 +1,430 @@
+//! Policies for destinations which already exist outside the rename set.
+//!
+//! Policies are applied while planning, by rewriting the mapping
+//! itself: skipped entries are dropped, suffixed entries get a new
+//! destination and backups become additional mappings from the
+//! existing destination to its backup name. Everything computed from
+//! the mapping afterwards therefore sees the final names.
+//!
+//! A backup never replaces anything: if `<dst>~` exists too, the
+//! `backup` policy fails like `refuse` does.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::super::filepath::clean;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Conflict {
+    /// Raise an error.
+    Refuse,
+    /// Leave the source where it is.
+    Skip,
+    /// Replace the existing destination.
+    Overwrite,
+    /// Move the existing destination to `<dst>~` first.
+    Backup,
+    /// Move the existing destination to `<dst>.~N~` first.
+    NumberedBackup,
+    /// Rename to `<stem> (N)<.ext>` instead.
+    Suffix,
+    /// Rename to `<stem>_N<.ext>` instead, starting from 2.
+    SuffixUnderscore,
+}
+
+impl Conflict {
+    pub const NAMES: [&'static str; 7] = [
+        "refuse",
+        "skip",
+        "overwrite",
+        "backup",
+        "numbered-backup",
+        "suffix",
+        "suffix-underscore",
+    ];
+}
+
+impl FromStr for Conflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refuse" => Ok(Conflict::Refuse),
+            "skip" => Ok(Conflict::Skip),
+            "overwrite" => Ok(Conflict::Overwrite),
+            "backup" => Ok(Conflict::Backup),
+            "numbered-backup" => Ok(Conflict::NumberedBackup),
+            "suffix" => Ok(Conflict::Suffix),
+            "suffix-underscore" => Ok(Conflict::SuffixUnderscore),
+            _ => Err(format!("Unknown conflict policy {}", s)),
+        }
+    }
+}
+
+/// A mapping with every conflict settled.
+#[derive(Debug, Default)]
+pub struct Resolved {
+    pub renames: HashMap<PathBuf, PathBuf>,
+    /// Existing destinations moved to a backup name, sorted. Their
+    /// moves are part of `renames`.
+    pub backups: Vec<PathBuf>,
+}
+
+/// Returns `files` with `policy` applied to every destination which
+/// exists but is neither a source nor in `removed` (paths which are
+/// moved away by other means, Eg. trashed). `keep_sources` is set
+/// when sources are copied or linked, so they stay in place.
+pub fn resolve_conflicts<P: AsRef<Path>>(
+    files: &HashMap<P, P>,
+    removed: &[PathBuf],
+    keep_sources: bool,
+    policy: Conflict,
+) -> Result<Resolved, String> {
+    let mut freed = removed.iter().map(clean).collect::<HashSet<PathBuf>>();
+    if !keep_sources {
+        freed.extend(files.keys().map(clean));
+    }
+
+    // Resolve in a fixed order so generated names do not depend on
+    // HashMap iteration.
+    let mut entries = files
+        .iter()
+        .map(|(src, dst)| (src.as_ref().to_path_buf(), dst.as_ref().to_path_buf()))
+        .collect::<Vec<(PathBuf, PathBuf)>>();
+    entries.sort();
+
+    // A skipped source stays where it is, so it is not freed after
+    // all and may conflict with another entry. Start over until no
+    // skipped source is left in freed.
+    loop {
+        let (resolved, skipped) = resolve_once(files, &entries, &freed, policy)?;
+        let kept = skipped
+            .iter()
+            .map(clean)
+            .filter(|src| freed.contains(src))
+            .collect::<Vec<PathBuf>>();
+        if kept.is_empty() {
+            return Ok(resolved);
+        }
+        for src in kept {
+            freed.remove(&src);
+        }
+    }
+}
+
+/// Applies policy to sorted entries given the paths which are freed.
+/// Returns the resolved mapping and the skipped sources.
+fn resolve_once<P: AsRef<Path>>(
+    files: &HashMap<P, P>,
+    entries: &[(PathBuf, PathBuf)],
+    freed: &HashSet<PathBuf>,
+    policy: Conflict,
+) -> Result<(Resolved, Vec<PathBuf>), String> {
+    // Every destination which will exist after renaming. New names
+    // must avoid these as well as what is on disk.
+    let mut taken = files.values().map(clean).collect::<HashSet<PathBuf>>();
+
+    let mut renames = HashMap::<PathBuf, PathBuf>::new();
+    let mut backups = Vec::<PathBuf>::new();
+    let mut skipped = Vec::<PathBuf>::new();
+    let mut errors = Vec::<String>::new();
+
+    for (src, dst) in entries.iter().cloned() {
+        let cleaned_dst = clean(&dst);
+        let conflict = clean(&src) != cleaned_dst
+            && !freed.contains(&cleaned_dst)
+            && fs::symlink_metadata(&cleaned_dst).is_ok();
+        if !conflict {
+            renames.insert(src, dst);
+            continue;
+        }
+
+        match policy {
+            Conflict::Refuse => {
+                errors.push(format!("Destination exists {}", cleaned_dst.display()))
+            }
+            Conflict::Skip => skipped.push(src),
+            Conflict::Overwrite => {
+                renames.insert(src, dst);
+            }
+            Conflict::Backup => {
+                let mut backup = cleaned_dst.clone().into_os_string();
+                backup.push("~");
+                let backup = PathBuf::from(backup);
+                if taken.contains(&backup)
+                    || (!freed.contains(&backup) && fs::symlink_metadata(&backup).is_ok())
+                {
+                    errors.push(format!("Backup exists {}", backup.display()));
+                    continue;
+                }
+                taken.insert(backup.clone());
+                backups.push(cleaned_dst.clone());
+                renames.insert(cleaned_dst, backup);
+                renames.insert(src, dst);
+            }
+            Conflict::NumberedBackup => {
+                let backup = free_name(&taken, 1, |n| {
+                    let mut name = cleaned_dst.clone().into_os_string();
+                    name.push(format!(".~{}~", n));
+                    PathBuf::from(name)
+                });
+                taken.insert(backup.clone());
+                backups.push(cleaned_dst.clone());
+                renames.insert(cleaned_dst, backup);
+                renames.insert(src, dst);
+            }
+            Conflict::Suffix => {
+                let new_dst = free_name(&taken, 1, |n| with_suffix(&cleaned_dst, " (", n, ")"));
+                taken.insert(new_dst.clone());
+                renames.insert(src, new_dst);
+            }
+            Conflict::SuffixUnderscore => {
+                let new_dst = free_name(&taken, 2, |n| with_suffix(&cleaned_dst, "_", n, ""));
+                taken.insert(new_dst.clone());
+                renames.insert(src, new_dst);
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
+    backups.sort();
+    Ok((Resolved { renames, backups }, skipped))
+}
+
+/// Returns the first name generated for N = first, first + 1, ...
+/// which is not taken and does not exist.
+fn free_name<F: Fn(usize) -> PathBuf>(taken: &HashSet<PathBuf>, first: usize, name: F) -> PathBuf {
+    let mut n = first;
+    loop {
+        let path = name(n);
+        if !taken.contains(&path) && fs::symlink_metadata(&path).is_err() {
+            return path;
+        }
+        n += 1;
+    }
+}
+
+/// Inserts `<open>N<close>` before the extension, Eg. `a/foo.txt` ->
+/// `a/foo (2).txt` or `a/foo_2.txt`.
+fn with_suffix(path: &Path, open: &str, n: usize, close: &str) -> PathBuf {
+    let mut name = OsString::new();
+    if let Some(stem) = path.file_stem() {
+        name.push(stem);
+    }
+    name.push(format!("{}{}{}", open, n, close));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::super::super::ioutils::temp_dir;
+    use super::super::{rename_logged, undo, Log, Mode, Options};
+    use super::{resolve_conflicts, with_suffix, Conflict};
+
+    fn resolve(
+        dir: &Path,
+        files: &[(&str, &str)],
+        policy: Conflict,
+    ) -> Result<Vec<(String, String)>, String> {
+        let map = files
+            .iter()
+            .map(|&(src, dst)| (dir.join(src), dir.join(dst)))
+            .collect::<HashMap<PathBuf, PathBuf>>();
+
+        let mut resolved = resolve_conflicts(&map, &[dir.join("trashed")], false, policy)?
+            .renames
+            .into_iter()
+            .map(|(src, dst)| {
+                let strip = |path: &Path| {
+                    path.strip_prefix(dir)
+                        .unwrap()
+                        .to_str()
+                        .unwrap()
+                        .to_string()
+                };
+                (strip(&src), strip(&dst))
+            })
+            .collect::<Vec<(String, String)>>();
+        resolved.sort();
+
+        Ok(resolved)
+    }
+
+    fn owned(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items
+            .iter()
+            .map(|&(src, dst)| (src.to_string(), dst.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn apply_policies() {
+        let dir = PathBuf::from(temp_dir("", "mmv-conflict-").unwrap());
+        for file in &[
+            "foo",
+            "bar",
+            "baz.txt",
+            "baz (1).txt",
+            "trashed",
+            "baz.txt.~1~",
+        ] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+
+        // bar is moved away and trashed is removed, so only baz.txt conflicts.
+        let files = [("foo", "bar"), ("bar", "baz.txt"), ("qux", "trashed")];
+
+        let err = resolve(&dir, &files, Conflict::Refuse).unwrap_err();
+        assert_eq!(
+            err,
+            format!("Destination exists {}", dir.join("baz.txt").display())
+        );
+
+        // bar stays, so foo cannot replace it either.
+        assert_eq!(
+            resolve(&dir, &files, Conflict::Skip).unwrap(),
+            owned(&[("qux", "trashed")])
+        );
+        assert_eq!(
+            resolve(&dir, &files, Conflict::Overwrite).unwrap(),
+            owned(&[("bar", "baz.txt"), ("foo", "bar"), ("qux", "trashed")])
+        );
+        assert_eq!(
+            resolve(&dir, &files, Conflict::Backup).unwrap(),
+            owned(&[
+                ("bar", "baz.txt"),
+                ("baz.txt", "baz.txt~"),
+                ("foo", "bar"),
+                ("qux", "trashed")
+            ])
+        );
+        assert_eq!(
+            resolve(&dir, &files, Conflict::NumberedBackup).unwrap(),
+            owned(&[
+                ("bar", "baz.txt"),
+                ("baz.txt", "baz.txt.~2~"),
+                ("foo", "bar"),
+                ("qux", "trashed")
+            ])
+        );
+        assert_eq!(
+            resolve(&dir, &files, Conflict::Suffix).unwrap(),
+            owned(&[("bar", "baz (2).txt"), ("foo", "bar"), ("qux", "trashed")])
+        );
+
+        fs::write(dir.join("baz_2.txt"), "").unwrap();
+        assert_eq!(
+            resolve(&dir, &files, Conflict::SuffixUnderscore).unwrap(),
+            owned(&[("bar", "baz_3.txt"), ("foo", "bar"), ("qux", "trashed")])
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn existing_backup() {
+        let dir = PathBuf::from(temp_dir("", "mmv-conflict-").unwrap());
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("b"), "b").unwrap();
+
+        let map = |files: &[(&str, &str)]| {
+            files
+                .iter()
+                .map(|&(src, dst)| (dir.join(src), dir.join(dst)))
+                .collect::<HashMap<PathBuf, PathBuf>>()
+        };
+        let resolved =
+            resolve_conflicts(&map(&[("a", "b")]), &[], false, Conflict::Backup).unwrap();
+        assert_eq!(resolved.backups, vec![dir.join("b")]);
+
+        // b~ is never replaced, unless it is moved away itself.
+        fs::write(dir.join("b~"), "old").unwrap();
+        assert_eq!(
+            resolve_conflicts(&map(&[("a", "b")]), &[], false, Conflict::Backup).unwrap_err(),
+            format!("Backup exists {}", dir.join("b~").display())
+        );
+        let files = map(&[("a", "b"), ("b~", "c")]);
+        let resolved = resolve_conflicts(&files, &[], false, Conflict::Backup).unwrap();
+        assert_eq!(resolved.renames.get(&dir.join("b")), Some(&dir.join("b~")));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skipped_source_stays() {
+        let dir = PathBuf::from(temp_dir("", "mmv-conflict-").unwrap());
+        for file in &["a", "b", "c"] {
+            fs::write(dir.join(file), file).unwrap();
+        }
+
+        // b is outside the set, so a stays, and c must not replace it.
+        // d would replace c, which stays as well.
+        let files = [("a", "b"), ("c", "a"), ("d", "c")];
+        assert_eq!(resolve(&dir, &files, Conflict::Skip).unwrap(), owned(&[]));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_final_names() {
+        let dir = PathBuf::from(temp_dir("", "mmv-conflict-").unwrap());
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("b"), "b").unwrap();
+
+        let files = [(dir.join("a"), dir.join("b"))]
+            .iter()
+            .cloned()
+            .collect::<HashMap<PathBuf, PathBuf>>();
+        let resolved = resolve_conflicts(&files, &[], false, Conflict::Suffix)
+            .unwrap()
+            .renames;
+        let mut log = Log::default();
+        rename_logged(&resolved, None, &Options::default(), &mut log).unwrap();
+
+        // The journal holds the suffixed name, so undoing it
+        // brings a back without touching b.
+        assert_eq!(log.edges.len(), 1);
+        assert_eq!(log.edges[0].dst, Some(dir.join("b (1)")));
+        undo(&mut log, Mode::Move).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("a")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dir.join("b")).unwrap(), "b");
+        assert!(!dir.join("b (1)").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn suffix_names() {
+        assert_eq!(
+            with_suffix(Path::new("a/foo.txt"), " (", 1, ")"),
+            PathBuf::from("a/foo (1).txt")
+        );
+        assert_eq!(
+            with_suffix(Path::new("foo"), " (", 2, ")"),
+            PathBuf::from("foo (2)")
+        );
+        assert_eq!(
+            with_suffix(Path::new(".bashrc"), " (", 1, ")"),
+            PathBuf::from(".bashrc (1)")
+        );
+        assert_eq!(
+            with_suffix(Path::new("a/foo.tar.gz"), "_", 2, ""),
+            PathBuf::from("a/foo.tar_2.gz")
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Copy, hard link and symbolic link counterparts of `rename()`.
+//!
+//! Since sources are left in place, there are no cycles to break
+//! and edges can run in any order. Destinations however must not
+//! exist yet, including destinations which are also sources.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use super::graph::Graph;
+use super::{sync_edges, Edge, Hop, Log, Mode, Options};
+
+pub fn copy<P: AsRef<Path>>(
+    files: &HashMap<P, P>,
+    dir: Option<&str>,
+    options: &Options,
+    log: &mut Log,
+) -> Result<(), String> {
+    let dir_path = PathBuf::from(dir.unwrap_or(""));
+    let edges = build_copies(files, &dir_path)?;
+
+    for (i, edge) in edges.iter().enumerate() {
+        let (src, dst) = (dir_path.join(&edge.src), dir_path.join(&edge.dst));
+        if let Err(err) = log.edge(&src, &dst, || do_copy(&src, &dst, options.mode)) {
+            // Remove every destination created so far.
+            // This does not undo directory creation.
+            for edge in edges[..i].iter().rev() {
+                let dst = dir_path.join(&edge.dst);
+                log.undo(&dst, None, || remove(&dst)).ok();
+            }
+
+            return Err(err.to_string());
+        }
+    }
+
+    if options.durable {
+        sync_edges(&edges, &dir_path).map_err(|err| err.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Returns the edges to copy sorted by destination, so that a
+/// destination directory is created before paths inside it.
+fn build_copies<P: AsRef<Path>>(files: &HashMap<P, P>, dir: &Path) -> Result<Vec<Edge>, String> {
+    let graph = Graph::new(files)?;
+
+    let mut edges = graph
+        .pairs()
+        .map(|(src, dst)| Edge {
+            src: graph.paths[src].clone(),
+            dst: graph.paths[dst].clone(),
+            hop: Hop::Direct,
+        })
+        .collect::<Vec<Edge>>();
+    edges.sort_by(|a, b| a.dst.cmp(&b.dst));
+
+    let collisions = edges
+        .iter()
+        .filter(|edge| fs::symlink_metadata(dir.join(&edge.dst)).is_ok())
+        .map(|edge| format!("Destination exists {}", edge.dst.display()))
+        .collect::<Vec<String>>();
+    if !collisions.is_empty() {
+        return Err(collisions.join("\n"));
+    }
+
+    Ok(edges)
+}
+
+fn do_copy(src: &Path, dst: &Path, mode: Mode) -> Result<(), io::Error> {
+    // Check src exists before creating any directory.
+    fs::symlink_metadata(src)?;
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match mode {
+        Mode::Copy => copy_all(src, dst),
+        Mode::Link => fs::hard_link(src, dst),
+        Mode::Symlink => {
+            // Absolute target so the link works wherever dst is.
+            let target = fs::canonicalize(src)?;
+            symlink(&target, dst)
+        }
+        Mode::Move => fs::rename(src, dst),
+    }
+}
+
+/// Recursively copies files, directories and symbolic links. dst is
+/// never replaced, and is removed again if copying fails partway.
+fn copy_all(src: &Path, dst: &Path) -> Result<(), io::Error> {
+    let file_type = fs::symlink_metadata(src)?.file_type();
+    if file_type.is_symlink() {
+        return symlink(&fs::read_link(src)?, dst);
+    }
+
+    // Creating dst fails if it exists, so anything removed below was
+    // created by this call.
+    let result = if file_type.is_dir() {
+        fs::create_dir(dst)?;
+        copy_dir(src, dst)
+    } else {
+        let mut reader = File::open(src)?;
+        let mut writer = OpenOptions::new().write(true).create_new(true).open(dst)?;
+        io::copy(&mut reader, &mut writer)
+            .and_then(|_| writer.set_permissions(reader.metadata()?.permissions()))
+    };
+    if result.is_err() {
+        remove(dst).ok();
+    }
+
+    result
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> Result<(), io::Error> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        copy_all(&entry.path(), &dst.join(entry.file_name()))?;
+    }
+    fs::set_permissions(dst, fs::metadata(src)?.permissions())
+}
+
+pub(super) fn remove(path: &Path) -> Result<(), io::Error> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+
+    use super::super::super::ioutils::temp_dir;
+    use super::copy_all;
+
+    #[test]
+    fn remove_partial_copy() {
+        let dir = PathBuf::from(temp_dir("", "mmv-copy-").unwrap());
+        fs::create_dir(dir.join("src")).unwrap();
+        fs::write(dir.join("src/file"), "file").unwrap();
+        // Sockets cannot be opened, so copying fails partway.
+        let _listener = UnixListener::bind(dir.join("src/socket")).unwrap();
+
+        assert!(copy_all(&dir.join("src"), &dir.join("dst")).is_err());
+        assert!(fs::symlink_metadata(dir.join("dst")).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn never_replace_destination() {
+        let dir = PathBuf::from(temp_dir("", "mmv-copy-").unwrap());
+        fs::write(dir.join("src"), "src").unwrap();
+        fs::write(dir.join("dst"), "dst").unwrap();
+
+        let err = copy_all(&dir.join("src"), &dir.join("dst")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        // Not created by this run, so it is left alone.
+        assert_eq!(fs::read_to_string(dir.join("dst")).unwrap(), "dst");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
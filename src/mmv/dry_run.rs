@@ -0,0 +1,255 @@
+//! Dry run of a rename.
+//!
+//! The part of the tree a rename touches (sources, destinations, their
+//! directories and permissions) is copied into a `MemFs`, and the exact
+//! edges of a real run are executed on it. Every edge which would fail
+//! is reported, then the final state is checked against the requested
+//! mapping: each source must end up at its destination, and nothing
+//! else may disappear.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use super::super::filepath::clean;
+use super::super::filesystem::{Filesystem, MemFs, OsFs};
+use super::{build_chains, do_rename, parallel, Edge, Hop};
+
+/// Simulates moving files, after moving `trash` to the trash and
+/// before creating the directories in `create`. `overwrite` allows
+/// replacing destinations which exist.
+///
+/// Returns the edges a real run would execute, or every problem found.
+pub fn dry_run<P: AsRef<Path>>(
+    files: &HashMap<P, P>,
+    trash: &[PathBuf],
+    create: &[PathBuf],
+    overwrite: bool,
+) -> Result<Vec<Edge>, String> {
+    // The tree in memory is loaded from the edges, so temporary names
+    // are picked on disk.
+    let (edges, starts) = build_chains(&OsFs, files)?;
+
+    let mut paths = Vec::<&Path>::new();
+    for edge in &edges {
+        paths.push(&edge.src);
+        paths.push(&edge.dst);
+    }
+    paths.extend(trash.iter().map(PathBuf::as_path));
+    paths.extend(create.iter().map(PathBuf::as_path));
+    let fs = MemFs::load(&paths).map_err(|err| format!("Error reading files:\n{}", err))?;
+
+    simulate(&fs, files, &edges, &starts, trash, create, overwrite)?;
+    Ok(edges)
+}
+
+fn simulate<P: AsRef<Path>>(
+    fs: &MemFs,
+    files: &HashMap<P, P>,
+    edges: &[Edge],
+    starts: &[usize],
+    trash: &[PathBuf],
+    create: &[PathBuf],
+    overwrite: bool,
+) -> Result<(), String> {
+    let before = fs.entries();
+    let mut problems = Vec::<String>::new();
+
+    for path in trash {
+        if let Err(err) = fs.remove_all(path) {
+            problems.push(format!(
+                "Moving {} to the trash would fail: {}",
+                path.display(),
+                err
+            ));
+        }
+    }
+
+    // Groups do not depend on each other, so the rest can still be
+    // checked when one of them fails.
+    for group in parallel::group_chains(edges, starts) {
+        for edge in group {
+            if let Err(err) = do_rename(fs, &edge.src, &edge.dst, edge.hop == Hop::ToTemp) {
+                problems.push(format!(
+                    "Renaming {} to {} would fail: {}",
+                    edge.src.display(),
+                    edge.dst.display(),
+                    err
+                ));
+                break;
+            }
+        }
+    }
+
+    for dir in create {
+        if let Err(err) = fs.create_dir_all(dir) {
+            problems.push(format!("Creating {} would fail: {}", dir.display(), err));
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(problems.join("\n"));
+    }
+
+    let mut renames = files
+        .iter()
+        .map(|(src, dst)| (clean(src), clean(dst)))
+        .filter(|(src, dst)| src != dst)
+        .collect::<Vec<(PathBuf, PathBuf)>>();
+    renames.sort();
+    for (src, dst) in &renames {
+        if fs.contents(dst) != Some(label(src)) {
+            problems.push(format!(
+                "{} would not end up at {}",
+                src.display(),
+                dst.display()
+            ));
+        }
+    }
+
+    // Every file and directory which was there must still be, unless
+    // it was trashed or replaced on purpose.
+    let after = fs
+        .entries()
+        .into_iter()
+        .map(|(_, label)| label)
+        .collect::<HashSet<Vec<u8>>>();
+    let replaced = renames
+        .iter()
+        .map(|(_, dst)| dst)
+        .filter(|_| overwrite)
+        .collect::<HashSet<&PathBuf>>();
+    for (path, label) in before {
+        let trashed = trash.iter().any(|dir| path.starts_with(clean(dir)));
+        if !label.is_empty() && !after.contains(&label) && !trashed && !replaced.contains(&path) {
+            problems.push(format!("Would overwrite {}", path.display()));
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(problems.join("\n"));
+    }
+
+    Ok(())
+}
+
+/// Label `MemFs::load()` gives to path.
+fn label(path: &Path) -> Vec<u8> {
+    path.as_os_str().as_encoded_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::super::super::filesystem::MemFs;
+    use super::super::super::ioutils::temp_dir;
+    use super::super::build_chains;
+    use super::{dry_run, simulate};
+
+    fn files(items: &[(&str, &str)]) -> HashMap<PathBuf, PathBuf> {
+        items
+            .iter()
+            .map(|&(src, dst)| (PathBuf::from(src), PathBuf::from(dst)))
+            .collect()
+    }
+
+    fn run_on(
+        fs: &MemFs,
+        items: &[(&str, &str)],
+        trash: &[&str],
+        overwrite: bool,
+    ) -> Result<(), String> {
+        let files = files(items);
+        let (edges, starts) = build_chains(fs, &files).unwrap();
+        let trash = trash.iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+        simulate(fs, &files, &edges, &starts, &trash, &[], overwrite)
+    }
+
+    #[test]
+    fn dry_run_on_disk() {
+        let dir = PathBuf::from(temp_dir("", "mmv-dry-run-").unwrap());
+        for name in &["a", "b", "c"] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+        let renames = [
+            (dir.join("a"), dir.join("b")),
+            (dir.join("b"), dir.join("a")),
+            (dir.join("c"), dir.join("sub/new/c")),
+        ]
+        .iter()
+        .cloned()
+        .collect::<HashMap<PathBuf, PathBuf>>();
+
+        let edges = dry_run(&renames, &[], &[dir.join("made")], false).unwrap();
+        assert_eq!(edges.len(), 4);
+
+        // Nothing was touched.
+        let mut names = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<String>>();
+        names.sort();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        for name in &["a", "b", "c"] {
+            assert_eq!(fs::read_to_string(dir.join(name)).unwrap(), *name);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn report_failing_edges() {
+        let fs = MemFs::new();
+        for path in &["ro/a", "p", "d/e", "f"] {
+            fs.write(path, path).unwrap();
+        }
+        fs.create_dir("d", b"d").unwrap();
+        fs.protect("ro");
+
+        let err = run_on(
+            &fs,
+            &[
+                ("ro/a", "b"),
+                ("missing", "x"),
+                ("p", "q"),
+                // d moves before d/e, which is then gone.
+                ("d", "z"),
+                ("d/e", "w"),
+                ("f", "ro/f"),
+            ],
+            &[],
+            false,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err,
+            "Renaming d/e to w would fail: No such file or directory (os error 2)\n\
+             Renaming f to ro/f would fail: Permission denied (os error 13)\n\
+             Renaming missing to x would fail: No such file or directory (os error 2)\n\
+             Renaming ro/a to b would fail: Permission denied (os error 13)"
+        );
+    }
+
+    #[test]
+    fn report_overwrites() {
+        let fs = MemFs::new();
+        for path in &["a", "b", "old/c"] {
+            fs.write(path, path).unwrap();
+        }
+
+        assert_eq!(
+            run_on(&fs, &[("a", "b")], &[], false),
+            Err(String::from("Would overwrite b"))
+        );
+
+        let fs = MemFs::new();
+        for path in &["a", "b", "old/c"] {
+            fs.write(path, path).unwrap();
+        }
+        assert_eq!(run_on(&fs, &[("a", "b")], &["old"], true), Ok(()));
+        assert_eq!(fs.files().len(), 1);
+    }
+}
@@ -0,0 +1,85 @@
+//! Backends executing rename edges.
+//!
+//! Edges come in groups which do not depend on each other (see
+//! `parallel::group_chains()`), so a backend may run groups in any
+//! order or at once, as long as edges within a group run in order.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use super::super::filesystem::Filesystem;
+use super::{run_edges, undo_edges, Edge, Log};
+
+pub trait Executor: Sync {
+    /// Executes every group on fs. On failure, every executed edge is
+    /// undone.
+    fn run(
+        &self,
+        fs: &dyn Filesystem,
+        groups: &[Vec<&Edge>],
+        dir: &Path,
+        log: &mut Log,
+    ) -> Result<(), String>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// One rename() call per edge.
+    Std,
+    /// Batches of renames submitted through io_uring, on Linux.
+    IoUring,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "std" => Ok(Backend::Std),
+            "io-uring" => Ok(Backend::IoUring),
+            _ => Err(format!("Unknown backend {}", s)),
+        }
+    }
+}
+
+/// Returns the executor for backend. Falls back to `StdExecutor` if
+/// the kernel does not support io_uring renames.
+pub fn new(backend: Backend) -> Result<Box<dyn Executor>, String> {
+    match backend {
+        Backend::Std => Ok(Box::new(StdExecutor)),
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        Backend::IoUring => Ok(match super::uring::UringExecutor::new() {
+            Some(executor) => Box::new(executor),
+            None => Box::new(StdExecutor),
+        }),
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        Backend::IoUring => Err(String::from(
+            "mmv was built without io_uring support (feature io-uring)",
+        )),
+    }
+}
+
+/// Renames one edge at a time, group after group.
+pub struct StdExecutor;
+
+impl Executor for StdExecutor {
+    fn run(
+        &self,
+        fs: &dyn Filesystem,
+        groups: &[Vec<&Edge>],
+        dir: &Path,
+        log: &mut Log,
+    ) -> Result<(), String> {
+        for (i, group) in groups.iter().enumerate() {
+            // A failing group undoes its own edges.
+            if let Err(err) = run_edges(fs, group, dir, log) {
+                for group in groups[..i].iter().rev() {
+                    undo_edges(fs, group, dir, log);
+                }
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+//! Rename graph over interned paths.
+//!
+//! Every distinct (cleaned) path is stored once, and edges refer to
+//! paths by their index. Since a path can be the source of at most one
+//! rename and the destination of at most one other, the graph is two
+//! arrays of indices, and walking it never hashes or compares paths.
+//! This keeps planning millions of renames cheap in time and memory.
+
+use std::collections::{BTreeSet, HashMap};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use super::super::filepath::clean;
+
+pub static EMPTY_PATH_ERROR: &str = "Path cannot be empty.";
+
+/// Marks a missing edge.
+pub const NONE: usize = usize::MAX;
+
+#[derive(Debug)]
+pub struct Graph {
+    /// Sorted, distinct paths.
+    pub paths: Vec<PathBuf>,
+    /// `next[i]` is the destination of path i, or NONE.
+    pub next: Vec<usize>,
+    /// `prev[i]` is the path renamed to path i, or NONE.
+    pub prev: Vec<usize>,
+}
+
+impl Graph {
+    /// Cleans all paths in files and raises an error if a path is
+    /// empty, if src/dst are repeated or if a path would move into
+    /// itself. Mappings where src == dst are left out of the graph.
+    ///
+    /// Every problem is reported, one per line, in a fixed order.
+    pub fn new<P: AsRef<Path>>(files: &HashMap<P, P>) -> Result<Self, String> {
+        let mut has_empty = false;
+        // Sources at even and destinations at odd positions.
+        let mut flat = Vec::<PathBuf>::with_capacity(files.len() * 2);
+        for (src, dst) in files {
+            if src.as_ref().as_os_str().is_empty() || dst.as_ref().as_os_str().is_empty() {
+                has_empty = true;
+                continue;
+            }
+
+            flat.push(clean(src));
+            flat.push(clean(dst));
+        }
+
+        let (paths, ids) = intern(flat);
+        let mut graph = Graph {
+            next: vec![NONE; paths.len()],
+            prev: vec![NONE; paths.len()],
+            paths,
+        };
+
+        let mut dup_srcs = BTreeSet::<usize>::new();
+        let mut dup_dsts = BTreeSet::<usize>::new();
+        let mut nested = BTreeSet::<(usize, usize)>::new();
+        // Sources and destinations are checked independently, so the
+        // errors do not depend on the order of files. The graph is only
+        // consistent if there are none.
+        for pair in ids.chunks(2) {
+            let (src, dst) = (pair[0], pair[1]);
+            if graph.next[src] == NONE {
+                graph.next[src] = dst;
+            } else {
+                dup_srcs.insert(src);
+            }
+            if graph.prev[dst] == NONE {
+                graph.prev[dst] = src;
+            } else {
+                dup_dsts.insert(dst);
+            }
+            if src != dst && is_inside(&graph.paths[dst], &graph.paths[src]) {
+                nested.insert((src, dst));
+            }
+        }
+
+        // Ids follow path order, so these come out sorted.
+        let mut errors = Vec::<String>::new();
+        if has_empty {
+            errors.push(EMPTY_PATH_ERROR.to_string());
+        }
+        errors.extend(
+            dup_srcs
+                .into_iter()
+                .map(|src| format!("Duplicate source {}", graph.paths[src].display())),
+        );
+        errors.extend(
+            dup_dsts
+                .into_iter()
+                .map(|dst| format!("Duplicate destination {}", graph.paths[dst].display())),
+        );
+        errors.extend(nested.into_iter().map(|(src, dst)| {
+            format!(
+                "Cannot move {} into itself ({})",
+                graph.paths[src].display(),
+                graph.paths[dst].display()
+            )
+        }));
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+
+        // Remove redundant mappings.
+        for i in 0..graph.paths.len() {
+            if graph.next[i] == i {
+                graph.next[i] = NONE;
+                graph.prev[i] = NONE;
+            }
+        }
+
+        Ok(graph)
+    }
+
+    /// Returns every (src, dst) pair, sorted by source.
+    pub fn pairs(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.next
+            .iter()
+            .enumerate()
+            .filter(|&(_, &dst)| dst != NONE)
+            .map(|(src, &dst)| (src, dst))
+    }
+}
+
+/// Returns true if path is inside dir. Both must be clean.
+fn is_inside(path: &Path, dir: &Path) -> bool {
+    // Comparing bytes first avoids splitting most paths into
+    // components.
+    path.as_os_str()
+        .as_encoded_bytes()
+        .starts_with(dir.as_os_str().as_encoded_bytes())
+        && path.starts_with(dir)
+}
+
+/// Sorts and deduplicates paths, moving each distinct path into the
+/// returned list once. Also returns the index in that list of every
+/// input path.
+fn intern(mut flat: Vec<PathBuf>) -> (Vec<PathBuf>, Vec<usize>) {
+    // Compare raw bytes, which is much cheaper than comparing paths
+    // component by component and just as deterministic. Keeping the
+    // bytes next to the index saves a lookup per comparison.
+    let mut keys = flat
+        .iter()
+        .enumerate()
+        .map(|(i, path)| (path.as_os_str().as_encoded_bytes(), i))
+        .collect::<Vec<(&[u8], usize)>>();
+    keys.sort_unstable();
+    let order = keys.into_iter().map(|(_, i)| i).collect::<Vec<usize>>();
+
+    let mut paths = Vec::<PathBuf>::new();
+    let mut ids = vec![0; flat.len()];
+    for i in order {
+        if paths.last().map(|last| last.as_os_str()) != Some(flat[i].as_os_str()) {
+            paths.push(mem::take(&mut flat[i]));
+        }
+        ids[i] = paths.len() - 1;
+    }
+
+    (paths, ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use super::{intern, Graph, NONE};
+
+    #[test]
+    fn intern_paths() {
+        let flat = ["b", "a", "b", "c", "a"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<PathBuf>>();
+        let (paths, ids) = intern(flat);
+
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")]
+        );
+        assert_eq!(ids, vec![1, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn build_graph() {
+        let files = [("a/", "b"), ("./b", "c"), ("d", "d")]
+            .iter()
+            .map(|&(src, dst)| (PathBuf::from(src), PathBuf::from(dst)))
+            .collect::<HashMap<PathBuf, PathBuf>>();
+        let graph = Graph::new(&files).unwrap();
+
+        // a -> b -> c, and d is left alone.
+        assert_eq!(graph.next, vec![1, 2, NONE, NONE]);
+        assert_eq!(graph.prev, vec![NONE, 0, 1, NONE]);
+        assert_eq!(graph.pairs().collect::<Vec<_>>(), vec![(0, 1), (1, 2)]);
+    }
+}
@@ -0,0 +1,93 @@
+//! Record of every executed edge, including the temporary hops used
+//! to break cycles and the edges undone after a failure.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Debug)]
+pub struct LogEntry {
+    pub src: PathBuf,
+    /// None when `src` was removed instead of moved.
+    pub dst: Option<PathBuf>,
+    pub started: SystemTime,
+    pub finished: SystemTime,
+    /// None when the edge succeeded.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Log {
+    pub edges: Vec<LogEntry>,
+    pub rollback: Vec<LogEntry>,
+    /// Files whose references were rewritten, with a `dst` of None.
+    /// Restoring one is recorded in `rollback` with `dst` equal to
+    /// `src`.
+    pub rewrites: Vec<LogEntry>,
+    /// Temporary names which were taken by the time their edge ran,
+    /// mapped to the fresh names used instead.
+    pub(super) temp_names: HashMap<PathBuf, PathBuf>,
+}
+
+impl Log {
+    /// Returns the name path was given when its edge ran, which
+    /// differs from the planned one for temporary names which were
+    /// taken.
+    pub(super) fn temp_name(&self, path: &Path) -> PathBuf {
+        match self.temp_names.get(path) {
+            Some(actual) => actual.clone(),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Runs `f` which moves `src` to `dst` and records it as an edge.
+    pub(super) fn edge<F>(&mut self, src: &Path, dst: &Path, f: F) -> io::Result<()>
+    where
+        F: FnOnce() -> io::Result<()>,
+    {
+        record(&mut self.edges, src, Some(dst), f)
+    }
+
+    /// Same as `edge()` for edges which undo a previous one. A `dst`
+    /// of None means `src` is removed.
+    pub(super) fn undo<F>(&mut self, src: &Path, dst: Option<&Path>, f: F) -> io::Result<()>
+    where
+        F: FnOnce() -> io::Result<()>,
+    {
+        record(&mut self.rollback, src, dst, f)
+    }
+
+    /// Runs `f` which rewrites the contents of path.
+    pub fn rewrite<F>(&mut self, path: &Path, f: F) -> io::Result<()>
+    where
+        F: FnOnce() -> io::Result<()>,
+    {
+        record(&mut self.rewrites, path, None, f)
+    }
+
+    /// Runs `f` which restores the contents of a rewritten path.
+    pub fn restore<F>(&mut self, path: &Path, f: F) -> io::Result<()>
+    where
+        F: FnOnce() -> io::Result<()>,
+    {
+        record(&mut self.rollback, path, Some(path), f)
+    }
+}
+
+fn record<F>(entries: &mut Vec<LogEntry>, src: &Path, dst: Option<&Path>, f: F) -> io::Result<()>
+where
+    F: FnOnce() -> io::Result<()>,
+{
+    let started = SystemTime::now();
+    let result = f();
+    entries.push(LogEntry {
+        src: src.to_path_buf(),
+        dst: dst.map(Path::to_path_buf),
+        started,
+        finished: SystemTime::now(),
+        error: result.as_ref().err().map(|err| err.to_string()),
+    });
+
+    result
+}
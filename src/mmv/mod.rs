@@ -1,81 +1,320 @@
-use std::collections::HashMap;
-use std::fs;
+mod conflict;
+mod copy;
+mod dry_run;
+mod executor;
+mod graph;
+mod log;
+mod parallel;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::process;
 
-use super::filepath::clean;
-use super::ioutils::next_random;
+use serde::{Deserialize, Serialize};
 
-static EMPTY_PATH_ERROR: &'static str = "Path cannot be empty.";
+use super::filesystem::{Filesystem, OsFs};
+use super::ioutils::random_hex;
+
+use self::graph::{Graph, NONE};
+
+pub use self::conflict::{resolve_conflicts, Conflict, Resolved};
+pub use self::dry_run::dry_run;
+pub use self::executor::{Backend, Executor, StdExecutor};
+pub use self::log::{Log, LogEntry};
 
 #[derive(Debug)]
 pub struct Edge {
     pub src: PathBuf,
     pub dst: PathBuf,
+    pub hop: Hop,
+}
+
+/// Whether an edge goes through a temporary name picked by
+/// `build_chains()` to break a cycle. Only these names are ever
+/// replaced by fresh ones, a requested destination is never
+/// mistaken for one whatever it is called.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hop {
+    /// From a source to its requested destination.
+    Direct,
+    /// From a source to a temporary name.
+    ToTemp,
+    /// From a temporary name to its requested destination.
+    FromTemp,
+}
+
+/// Prefix of the temporary names which break cycles, followed by
+/// the process id and random hex digits. A leftover one means a run
+/// was interrupted.
+pub const TEMP_PREFIX: &str = ".mmv-tmp-";
+
+impl AsRef<Edge> for Edge {
+    fn as_ref(&self) -> &Edge {
+        self
+    }
+}
+
+/// How each source is transferred to its destination.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Move,
+    Copy,
+    /// Hard link
+    Link,
+    Symlink,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub mode: Mode,
+    /// fsync() every affected directory once all edges are done, so
+    /// that the changes survive a crash or power loss.
+    pub durable: bool,
+    /// Number of threads renaming independent chains at once. Only
+    /// used when moving.
+    pub jobs: usize,
+    /// How edges are executed. Only used when moving.
+    pub backend: Backend,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            mode: Mode::Move,
+            durable: false,
+            jobs: 1,
+            backend: Backend::Std,
+        }
+    }
 }
 
 pub fn rename<P: AsRef<Path>>(files: &HashMap<P, P>, dir: Option<&str>) -> Result<(), String> {
-    let mut dir_path = "";
-    if dir.is_some() {
-        dir_path = dir.unwrap();
+    rename_with(files, dir, &Options::default())
+}
+
+/// Same as `rename()` but allows changing the default options.
+pub fn rename_with<P: AsRef<Path>>(
+    files: &HashMap<P, P>,
+    dir: Option<&str>,
+    options: &Options,
+) -> Result<(), String> {
+    rename_logged(files, dir, options, &mut Log::default())
+}
+
+/// Same as `rename_with()` but records every executed edge in `log`,
+/// including temporary hops and the edges undone after a failure.
+pub fn rename_logged<P: AsRef<Path>>(
+    files: &HashMap<P, P>,
+    dir: Option<&str>,
+    options: &Options,
+    log: &mut Log,
+) -> Result<(), String> {
+    rename_on(&OsFs, files, dir, options, log)
+}
+
+/// Same as `rename_logged()` but moves files on fs. Copying and
+/// linking, `durable` and the io-uring backend always use the
+/// operating system.
+pub fn rename_on<P: AsRef<Path>>(
+    fs: &dyn Filesystem,
+    files: &HashMap<P, P>,
+    dir: Option<&str>,
+    options: &Options,
+    log: &mut Log,
+) -> Result<(), String> {
+    if options.mode != Mode::Move {
+        return copy::copy(files, dir, options, log);
     }
 
-    // Closure to prepend leading directory to existing paths.
-    let prefix_paths = |src: &PathBuf, dst: &PathBuf| {
-        (
-            PathBuf::from(dir_path).join(src),
-            PathBuf::from(dir_path).join(dst),
-        )
-    };
+    let dir_path = Path::new(dir.unwrap_or(""));
+    let (renames, starts) = build_chains(fs, files)?;
 
-    match build_renames(files) {
-        Ok(renames) => {
-            for (i, rename) in renames.iter().enumerate() {
-                let (src, dst) = prefix_paths(&rename.src, &rename.dst);
-
-                if let Err(err) = do_rename(src.as_path(), dst.as_path()) {
-                    // Only undo if there is more than 1 previous renames.
-                    // Otherwise, j - 1 yields an overflow error (since i is usize).
-                    if i >= 1 {
-                        let mut j = i - 1;
-                        loop {
-                            // NOTE: dst is now the source path and vice-versa.
-                            let (src, dst) = prefix_paths(&renames[j].dst, &renames[j].src);
-                            // Undo on error not to leave the temporary files.
-                            // This does not undo directory creation.
-                            if let Err(_err) = fs::rename(src, dst) {
-                                break;
-                            }
-
-                            if j == 0 {
-                                break;
-                            }
-
-                            j -= 1;
-                        }
-                    }
+    let executor = executor::new(options.backend)?;
 
-                    return Err(err.to_string());
-                }
+    if options.jobs == 1 && options.backend == Backend::Std {
+        // Grouping is only needed to run groups concurrently.
+        run_edges(fs, &renames, dir_path, log)?;
+    } else {
+        let groups = parallel::group_chains(&renames, &starts);
+        parallel::execute(fs, &groups, dir_path, options.jobs, executor.as_ref(), log)?;
+    }
+
+    if options.durable {
+        sync_edges(&renames, dir_path).map_err(|err| err.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Executes edges in order. On failure, the edges already executed
+/// are undone.
+fn run_edges<E: AsRef<Edge>>(
+    fs: &dyn Filesystem,
+    edges: &[E],
+    dir: &Path,
+    log: &mut Log,
+) -> Result<(), String> {
+    for (i, edge) in edges.iter().enumerate() {
+        if let Err(err) = run_edge(fs, edge.as_ref(), dir, log) {
+            undo_edges(fs, &edges[..i], dir, log);
+            return Err(err.to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Renames the source of edge to its destination and logs it. A
+/// temporary name taken since it was picked is replaced with a fresh
+/// one, which later edges then use (see `Log::temp_name()`). The
+/// attempt with the taken name is logged as failed.
+fn run_edge(fs: &dyn Filesystem, edge: &Edge, dir: &Path, log: &mut Log) -> io::Result<()> {
+    let (src, dst) = (dir.join(&edge.src), dir.join(&edge.dst));
+    let to_temp = edge.hop == Hop::ToTemp;
+    let src = log.temp_name(&src);
+    let mut actual_dst = log.temp_name(&dst);
+    loop {
+        match log.edge(&src, &actual_dst, || {
+            do_rename(fs, &src, &actual_dst, to_temp)
+        }) {
+            Err(err) if to_temp && err.raw_os_error() == Some(libc::EEXIST) => {
+                let parent = dst.parent().unwrap_or_else(|| Path::new(""));
+                actual_dst = random_path(fs, parent).map_err(io::Error::other)?;
+                log.temp_names.insert(dst.clone(), actual_dst.clone());
             }
+            res => return res,
+        }
+    }
+}
 
-            Ok(())
+/// Renames every destination in edges back to its source, latest
+/// first. Stops at the first failure.
+fn undo_edges<E: AsRef<Edge>>(fs: &dyn Filesystem, edges: &[E], dir: &Path, log: &mut Log) {
+    for edge in edges.iter().rev() {
+        let edge = edge.as_ref();
+        // NOTE: dst is now the source path and vice-versa.
+        let (src, dst) = (
+            log.temp_name(&dir.join(&edge.dst)),
+            log.temp_name(&dir.join(&edge.src)),
+        );
+        // Undo on error not to leave the temporary files.
+        // This does not undo directory creation.
+        let rename = || {
+            if edge.hop == Hop::FromTemp {
+                fs.rename_noreplace(&src, &dst)
+            } else {
+                fs.rename(&src, &dst)
+            }
+        };
+        if let Err(_err) = log.undo(&src, Some(&dst), rename) {
+            break;
         }
-        Err(err) => Err(err),
     }
 }
 
-fn do_rename(src: &Path, dst: &Path) -> Result<(), io::Error> {
+/// Undoes every successful edge in log, latest first, when a later
+/// step fails once renaming is over. Copies and links are removed
+/// instead. Stops at the first failure.
+pub fn undo(log: &mut Log, mode: Mode) -> Result<(), String> {
+    undo_on(&OsFs, log, mode)
+}
+
+/// Same as `undo()` but moves files back on fs. Copies and links are
+/// always removed from the operating system.
+pub fn undo_on(fs: &dyn Filesystem, log: &mut Log, mode: Mode) -> Result<(), String> {
+    let done = log
+        .edges
+        .iter()
+        .filter(|entry| entry.error.is_none())
+        .filter_map(|entry| Some((entry.src.clone(), entry.dst.clone()?)))
+        .collect::<Vec<(PathBuf, PathBuf)>>();
+
+    for (src, dst) in done.iter().rev() {
+        let result = if mode == Mode::Move {
+            log.undo(dst, Some(src), || fs.rename(dst, src))
+        } else {
+            log.undo(dst, None, || copy::remove(dst))
+        };
+        result.map_err(|err| format!("Error undoing {}:\n{}", dst.display(), err))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the edges `rename()` would execute for files, in order,
+/// including temporary hops which break cycles.
+pub fn edges<P: AsRef<Path>>(files: &HashMap<P, P>) -> Result<Vec<Edge>, String> {
+    build_renames(files)
+}
+
+/// Flushes the directory entries changed by edges to disk.
+///
+/// Each directory is synced once no matter how many edges touch it,
+/// which keeps the cost low for large batches. Ancestors of
+/// destinations are synced too since they may have been created.
+fn sync_edges(edges: &[Edge], dir: &Path) -> Result<(), io::Error> {
+    let mut dirs = BTreeSet::<PathBuf>::new();
+    for edge in edges {
+        if let Some(parent) = dir.join(&edge.src).parent() {
+            dirs.insert(parent.to_path_buf());
+        }
+        for ancestor in dir.join(&edge.dst).ancestors().skip(1) {
+            dirs.insert(ancestor.to_path_buf());
+        }
+    }
+
+    sync_dirs(dirs)
+}
+
+/// Flushes the entries of every directory in `dirs` to disk, for
+/// changes made outside of renaming such as trashing files or
+/// creating directories.
+pub fn sync_dirs(dirs: BTreeSet<PathBuf>) -> Result<(), io::Error> {
+    for dir in dirs {
+        // Relative paths end with an empty ancestor for the current
+        // directory.
+        let dir = if dir.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            dir
+        };
+        File::open(&dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Renames src to dst, creating the parents of dst if needed. With
+/// `noreplace`, an existing dst is an error instead of being replaced.
+fn do_rename(
+    fs: &dyn Filesystem,
+    src: &Path,
+    dst: &Path,
+    noreplace: bool,
+) -> Result<(), io::Error> {
+    // Temporary names are never replaced, in case one was created
+    // after it was picked.
+    let rename = |src: &Path, dst: &Path| {
+        if noreplace {
+            fs.rename_noreplace(src, dst)
+        } else {
+            fs.rename(src, dst)
+        }
+    };
+
     // rename() raises io error iff:
     // 1. src does not exist in fs
     // 2. dst directory does not exist in fs
-    match fs::rename(src, dst) {
+    match rename(src, dst) {
         Ok(_res) => Ok(()), // successful rename, do nothing else
         Err(_err) => {
             // src does not exist in fs.
-            if let Err(err) = fs::metadata(src) {
-                return Err(err);
-            }
+            fs.metadata(src)?;
 
             // dst directory does not exist.
             if let Some(parent) = dst.parent() {
@@ -84,13 +323,11 @@ fn do_rename(src: &Path, dst: &Path) -> Result<(), io::Error> {
                 //
                 // Eg. parent(abc/def/ghi) -> abc/def
                 // So directories abc & def are created.
-                if let Err(err) = fs::create_dir_all(parent) {
-                    return Err(err);
-                }
+                fs.create_dir_all(parent)?;
             }
 
             // Try renaming again after creating directorie(s).
-            fs::rename(src, dst)
+            rename(src, dst)
         }
     }
 }
@@ -105,127 +342,102 @@ fn do_rename(src: &Path, dst: &Path) -> Result<(), io::Error> {
 /// So when adding back the edges to the output vector, the edges are pushed
 /// in reverse so that the files can be `moved` without overriding the contents
 /// of other files.
+///
+/// Groups are visited in sorted order of their sources, so the same
+/// input always yields the same edges (apart from temporary names).
 fn build_renames<P: AsRef<Path>>(files: &HashMap<P, P>) -> Result<Vec<Edge>, String> {
-    // Represents the reverse of files - where all edges are reversed.
-    // Eg. A -> B becomes B -> A
-    let mut rev = HashMap::<PathBuf, PathBuf>::new();
-
-    // Stores similar to files except each src/dst are replaced with
-    // canonicalize() paths.
-    let mut file_map = HashMap::<PathBuf, PathBuf>::new();
-
-    // Raise error if src/dst are repeated.
-    // Also construct file_map and rev along on the way.
-    for (src, dst) in files {
-        if src.as_ref().to_str() == Some("") || dst.as_ref().to_str() == Some("") {
-            return Err(EMPTY_PATH_ERROR.to_string());
-        }
+    build_chains(&OsFs, files).map(|(edges, _)| edges)
+}
 
-        let cleaned_src = clean(src);
-        let cleaned_dst = clean(dst);
+/// Same as `build_renames()` but also returns the index of the first
+/// edge of every chain or cycle, in order. Temporary names are picked
+/// so that they do not exist on fs.
+fn build_chains<P: AsRef<Path>>(
+    fs: &dyn Filesystem,
+    files: &HashMap<P, P>,
+) -> Result<(Vec<Edge>, Vec<usize>), String> {
+    let graph = Graph::new(files)?;
+    let (paths, next, prev) = (&graph.paths, &graph.next, &graph.prev);
+
+    let edge = |src: usize, dst: usize| Edge {
+        src: paths[src].clone(),
+        dst: paths[dst].clone(),
+        hop: Hop::Direct,
+    };
 
-        if file_map.contains_key(&cleaned_src) {
-            return Err(format!("Duplicate source {}", cleaned_src.display()));
-        }
+    let mut rs = Vec::<Edge>::new(); // return value
+    let mut starts = Vec::<usize>::new();
+    let mut visited = vec![false; paths.len()];
 
-        if rev.contains_key(&cleaned_dst) {
-            return Err(format!("Duplicate destination {}", cleaned_dst.display()));
+    for (_, start) in graph.pairs() {
+        if visited[start] {
+            // Skip groups that were already checked.
+            continue;
         }
 
-        file_map.insert(cleaned_src.clone(), cleaned_dst.clone());
-        rev.insert(cleaned_dst, cleaned_src);
-    }
+        starts.push(rs.len());
 
-    // Remove redundant mappings from both HashMap.
-    file_map.retain(|src, dst| src != dst);
-    rev.retain(|src, dst| src != dst);
-
-    // Find cyclic groups
-    let mut rs = Vec::<Edge>::new(); // return value
-    let mut vs = HashMap::<&PathBuf, i32>::new();
-    let mut cycle = false;
-    let mut i = 0;
-
-    for (_, mut dst) in &file_map {
-        if let Some(&group_num) = vs.get(dst) {
-            if group_num > 0 {
-                // Skip nodes that were already checked.
-                continue;
-            }
+        // Walk forward to the end of the chain, or back to start if
+        // the group is a cycle. Every path has at most one source and
+        // one destination, so no other node can be seen twice.
+        let mut end = start;
+        while next[end] != NONE && next[end] != start {
+            end = next[end];
         }
 
-        i += 1;
-
-        // Detect cycle
-        while let Some(dst_dst) = file_map.get(dst) {
-            vs.insert(&dst, i); // Set the group number to i.
-            dst = dst_dst;
-            if let Some(&group_num) = vs.get(dst_dst) {
-                if group_num > 0 {
-                    cycle = group_num == i;
-                    break;
-                }
+        if next[end] == NONE {
+            // Chain: rename from the end backwards, so that every
+            // destination is free before it is used.
+            let mut dst = end;
+            visited[dst] = true;
+            while prev[dst] != NONE {
+                rs.push(edge(prev[dst], dst));
+                dst = prev[dst];
+                visited[dst] = true;
             }
-        }
+        } else {
+            // Cycle: move start out of the way to break it, then
+            // rename backwards and finally move start into place.
+            let parent = paths[start].parent().unwrap_or_else(|| Path::new(""));
+            let tmp = random_path(fs, parent)?;
+            rs.push(Edge {
+                src: paths[start].clone(),
+                dst: tmp.clone(),
+                hop: Hop::ToTemp,
+            });
 
-        let mut tmp: PathBuf = PathBuf::new();
-        if cycle {
-            if let Some(path) = dst.parent() {
-                tmp = random_path(path);
-                rs.push(Edge {
-                    src: dst.to_owned(),
-                    dst: tmp.to_owned(),
-                });
+            let mut dst = start;
+            visited[dst] = true;
+            while prev[dst] != start {
+                rs.push(edge(prev[dst], dst));
+                dst = prev[dst];
+                visited[dst] = true;
             }
-            // Breaks the cycle (in later loop).
-            //
-            // Basically decrements one of the nodes
-            // in the cycle so that `vs[src] == i`
-            // returns false after going through all
-            // the nodes in the cycle.
-            *vs.get_mut(dst).unwrap() -= 1;
-        }
-
-        loop {
-            if let Some(src) = rev.get(dst) {
-                if !cycle || *vs.get(src).unwrap() == i {
-                    rs.push(Edge {
-                        src: src.to_owned(),
-                        dst: dst.to_owned(),
-                    });
-
-                    if !cycle {
-                        vs.insert(dst, i);
-                    }
 
-                    dst = src;
-                } else {
-                    break;
-                }
-            } else {
-                break;
-            }
-        }
-
-        if cycle {
-            // Insert last edge
             rs.push(Edge {
-                dst: dst.to_owned(),
                 src: tmp,
+                dst: paths[dst].clone(),
+                hop: Hop::FromTemp,
             });
         }
     }
 
-    Ok(rs)
+    Ok((rs, starts))
 }
 
-fn random_path(dir: &Path) -> PathBuf {
-    // Keep running till a path string is generated
-    // that does not exist in file system.
+/// Returns a temporary name in dir which does not exist on fs yet.
+/// Names come from the operating system's random generator, and
+/// renaming to them never replaces a path (see `do_rename()`), so a
+/// name taken in the meantime is replaced by `run_edge()` instead of
+/// losing a file.
+fn random_path(fs: &dyn Filesystem, dir: &Path) -> Result<PathBuf, String> {
     loop {
-        let new_path = dir.join(next_random());
-        if let Err(_err) = fs::metadata(&new_path) {
-            return new_path;
+        let random =
+            random_hex(8).map_err(|err| format!("Error picking a temporary name:\n{}", err))?;
+        let new_path = dir.join(format!("{}{}-{}", TEMP_PREFIX, process::id(), random));
+        // Dangling symbolic links count as taken.
+        if let Err(_err) = fs.symlink_metadata(&new_path) {
+            return Ok(new_path);
         }
     }
 }
@@ -237,16 +449,24 @@ mod tests {
     use std::fs;
     use std::hash::Hash;
     use std::io;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
+    use std::process;
     use std::sync::Once;
 
     use super::super::filepath::clean;
+    use super::super::filesystem::MemFs;
     use super::super::ioutils::temp_dir;
-    use super::{build_renames, rename, EMPTY_PATH_ERROR};
+    use super::graph::EMPTY_PATH_ERROR;
+    #[cfg(feature = "io-uring")]
+    use super::Backend;
+    use super::{
+        build_renames, rename_on, rename_with, run_edges, sync_dirs, undo_on, Log, Mode, Options,
+        TEMP_PREFIX,
+    };
 
     static SETUP: Once = Once::new();
 
-    const TESTS_DIR: &'static str = "mmv-tests";
+    const TESTS_DIR: &str = "mmv-tests";
 
     type CaseInput<'a> = &'a [(&'a str, &'a str)];
 
@@ -267,6 +487,7 @@ mod tests {
         pub expected: HashMap<PathBuf, String>,
         pub count: usize,
         pub err: Option<&'a str>,
+        pub options: Options,
     }
 
     impl<'a> TestCase<'a> {
@@ -283,9 +504,31 @@ mod tests {
                 contents: to_map::<PathBuf, String>(contents),
                 expected: to_map::<PathBuf, String>(expected),
                 err,
+                options: Options::default(),
             }
         }
 
+        pub fn mode(mut self, mode: Mode) -> Self {
+            self.options.mode = mode;
+            self
+        }
+
+        pub fn durable(mut self) -> Self {
+            self.options.durable = true;
+            self
+        }
+
+        pub fn jobs(mut self, jobs: usize) -> Self {
+            self.options.jobs = jobs;
+            self
+        }
+
+        #[cfg(feature = "io-uring")]
+        pub fn backend(mut self, backend: Backend) -> Self {
+            self.options.backend = backend;
+            self
+        }
+
         pub fn setup(&self, dir: &str) -> io::Result<()> {
             for (file, content) in &self.contents {
                 let loc = PathBuf::from(dir).join(file);
@@ -309,8 +552,8 @@ mod tests {
                     let cleaned_path = clean(pathbuf);
                     let read_result = fs::read(&cleaned_path);
                     let contents = String::from_utf8(read_result?);
-                    if contents.is_ok() {
-                        output_map.insert(cleaned_path, contents.unwrap());
+                    if let Ok(contents) = contents {
+                        output_map.insert(cleaned_path, contents);
                     } else {
                         eprintln!("Failed to read contents from {:?}", cleaned_path);
                         break;
@@ -343,13 +586,12 @@ mod tests {
 
             // Build renames
             let renames = build_renames(&self.files);
-            if renames.is_ok() {
-                let edges = renames.unwrap();
+            if let (Ok(edges), Mode::Move) = (renames, self.options.mode) {
                 assert!(edges.len() == self.count);
             }
 
             // Rename files in temporary folder path, not TEST_DIR path!
-            if let Err(err) = rename(&self.files, Some(&dir_path)) {
+            if let Err(err) = rename_with(&self.files, Some(&dir_path), &self.options) {
                 if let Some(expected_err) = self.err {
                     assert_eq!(err, expected_err.to_string());
                 }
@@ -529,6 +771,33 @@ mod tests {
         .check();
     }
 
+    #[test]
+    fn report_all_errors() {
+        TestCase::new(
+            0, // No matter
+            &[
+                ("", "x"),
+                ("foo", "baz"),
+                ("bar", "baz"),
+                ("qux", "foo/../baz"),
+                ("./qux", "a"),
+                ("dir", "dir/sub"),
+                ("b", "c"),
+                ("d", "c"),
+            ],
+            &[("foo", "0"), ("bar", "1")],
+            &[("foo", "0"), ("bar", "1")],
+            Some(
+                "Path cannot be empty.\n\
+                 Duplicate source qux\n\
+                 Duplicate destination baz\n\
+                 Duplicate destination c\n\
+                 Cannot move dir into itself (dir/sub)",
+            ),
+        )
+        .check();
+    }
+
     #[test]
     fn same_destination_with_error() {
         TestCase::new(
@@ -570,4 +839,494 @@ mod tests {
         )
         .check();
     }
+
+    #[test]
+    fn copy_files() {
+        TestCase::new(
+            0, // Not used when copying
+            &[("foo", "bar"), ("baz", "x/baz")],
+            &[("foo", "0"), ("baz", "1")],
+            &[("foo", "0"), ("bar", "0"), ("baz", "1"), ("x/baz", "1")],
+            None,
+        )
+        .mode(Mode::Copy)
+        .check();
+    }
+
+    #[test]
+    fn copy_existing_destination_error() {
+        TestCase::new(
+            0, // Not used when copying
+            &[("foo", "bar"), ("bar", "foo")],
+            &[("foo", "0"), ("bar", "1")],
+            &[("foo", "0"), ("bar", "1")],
+            Some("Destination exists bar\nDestination exists foo"),
+        )
+        .mode(Mode::Copy)
+        .check();
+    }
+
+    #[test]
+    fn link_files() {
+        for &mode in &[Mode::Link, Mode::Symlink] {
+            TestCase::new(
+                0, // Not used when linking
+                &[("foo", "bar"), ("baz", "x/baz")],
+                &[("foo", "0"), ("baz", "1")],
+                &[("foo", "0"), ("bar", "0"), ("baz", "1"), ("x/baz", "1")],
+                None,
+            )
+            .mode(mode)
+            .check();
+        }
+    }
+
+    #[test]
+    fn durable_rename() {
+        TestCase::new(
+            2,
+            &[("foo", "x/y/foo"), ("bar", "baz")],
+            &[("foo", "0"), ("bar", "1")],
+            &[("x/y/foo", "0"), ("baz", "1")],
+            None,
+        )
+        .durable()
+        .check();
+    }
+
+    #[test]
+    fn sync_other_dirs() {
+        let dir = PathBuf::from(temp_dir("", "mmv-sync-").unwrap());
+        let dirs = vec![PathBuf::new(), dir.clone()].into_iter().collect();
+        assert!(sync_dirs(dirs).is_ok());
+        let missing = vec![dir.join("missing")].into_iter().collect();
+        assert!(sync_dirs(missing).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parallel_rename() {
+        TestCase::new(
+            8,
+            &[
+                ("foo", "bar"),
+                ("bar", "foo"),
+                ("baz", "qux"),
+                ("qux", "quux"),
+                ("a", "x/a"),
+                ("b", "x/b"),
+                ("c", "d"),
+            ],
+            &[
+                ("foo", "0"),
+                ("bar", "1"),
+                ("baz", "2"),
+                ("qux", "3"),
+                ("a", "4"),
+                ("b", "5"),
+                ("c", "6"),
+            ],
+            &[
+                ("bar", "0"),
+                ("foo", "1"),
+                ("qux", "2"),
+                ("quux", "3"),
+                ("x/a", "4"),
+                ("x/b", "5"),
+                ("d", "6"),
+            ],
+            None,
+        )
+        .jobs(4)
+        .check();
+    }
+
+    #[test]
+    fn parallel_undo_on_error() {
+        // Whichever chains ran before the missing source is found are
+        // undone too.
+        TestCase::new(
+            7,
+            &[
+                ("foo", "bar"),
+                ("bar", "foo"),
+                ("baz", "qux"),
+                ("qux", "quux"),
+                ("quux", "baz"),
+            ],
+            &[("foo", "0"), ("bar", "1"), ("baz", "2"), ("qux", "3")],
+            &[("foo", "0"), ("bar", "1"), ("baz", "2"), ("qux", "3")],
+            Some("No such file or directory (os error 2)"),
+        )
+        .jobs(2)
+        .check();
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn io_uring_rename() {
+        for &jobs in &[1, 2] {
+            TestCase::new(
+                6,
+                &[
+                    ("foo", "bar"),
+                    ("bar", "foo"),
+                    ("baz", "x/baz"),
+                    ("qux", "quux"),
+                    ("quux", "corge"),
+                ],
+                &[
+                    ("foo", "0"),
+                    ("bar", "1"),
+                    ("baz", "2"),
+                    ("qux", "3"),
+                    ("quux", "4"),
+                ],
+                &[
+                    ("bar", "0"),
+                    ("foo", "1"),
+                    ("x/baz", "2"),
+                    ("quux", "3"),
+                    ("corge", "4"),
+                ],
+                None,
+            )
+            .backend(Backend::IoUring)
+            .jobs(jobs)
+            .check();
+        }
+    }
+
+    #[test]
+    fn deterministic_edges() {
+        let items = [("a", "b"), ("b", "a"), ("c", "d"), ("e", "c")];
+        let sources = items
+            .iter()
+            .map(|&(src, _)| PathBuf::from(src))
+            .collect::<Vec<PathBuf>>();
+
+        // Every HashMap iterates in a different order.
+        for _ in 0..10 {
+            let files = to_map::<PathBuf, PathBuf>(&items);
+            let edges = build_renames(&files)
+                .unwrap()
+                .into_iter()
+                .map(|edge| {
+                    // Temporary names are random.
+                    let name = |path: PathBuf| {
+                        if sources.contains(&path) || path == Path::new("d") {
+                            path.to_str().unwrap().to_string()
+                        } else {
+                            String::from("tmp")
+                        }
+                    };
+                    (name(edge.src), name(edge.dst))
+                })
+                .collect::<Vec<(String, String)>>();
+
+            assert_eq!(
+                edges,
+                [
+                    ("b", "tmp"),
+                    ("a", "b"),
+                    ("tmp", "a"),
+                    ("c", "d"),
+                    ("e", "c")
+                ]
+                .iter()
+                .map(|&(src, dst)| (src.to_string(), dst.to_string()))
+                .collect::<Vec<(String, String)>>()
+            );
+        }
+    }
+
+    #[test]
+    fn temp_names() {
+        let files = to_map::<PathBuf, PathBuf>(&[("x/a", "x/b"), ("x/b", "x/a")]);
+        let edges = build_renames(&files).unwrap();
+        let tmp = &edges[0].dst;
+
+        // Next to the cycle, and recognizable.
+        assert_eq!(tmp.parent(), Some(Path::new("x")));
+        let name = tmp.file_name().unwrap().to_str().unwrap();
+        let prefix = format!("{}{}-", TEMP_PREFIX, process::id());
+        assert!(name.starts_with(&prefix), "{}", name);
+        let random = &name[prefix.len()..];
+        assert_eq!(random.len(), 16);
+        assert!(random.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(build_renames(&files).unwrap()[0].dst, *tmp);
+    }
+
+    #[test]
+    fn never_replace_temp_name() {
+        let fs = mem_fs(&["a", "b"]);
+        let files = to_map::<PathBuf, PathBuf>(&[("a", "b"), ("b", "a")]);
+        let edges = build_renames(&files).unwrap();
+        // Someone else took the name after it was picked.
+        fs.write(&edges[0].dst, "other").unwrap();
+        let tmp = edges[0].dst.to_str().unwrap().to_string();
+        let mut log = Log::default();
+
+        run_edges(&fs, &edges, Path::new(""), &mut log).unwrap();
+        assert_eq!(
+            mem_files(&fs),
+            vec![
+                (tmp.clone(), String::from("other")),
+                (String::from("a"), String::from("b")),
+                (String::from("b"), String::from("a")),
+            ]
+        );
+        // The taken name failed, then a fresh one was used both ways.
+        assert_eq!(log.edges.len(), 4);
+        assert_eq!(
+            log.edges[0].error,
+            Some(String::from("File exists (os error 17)"))
+        );
+        let fresh = log.edges[1].dst.clone().unwrap();
+        assert_ne!(fresh, PathBuf::from(&tmp));
+        assert_eq!(log.edges[3].src, fresh);
+        // Fresh names only apply to the run which picked them.
+        assert_eq!(
+            Log::default().temp_name(Path::new(&tmp)),
+            PathBuf::from(&tmp)
+        );
+
+        // Undoing moves the file back through the fresh name as well.
+        undo_on(&fs, &mut log, Mode::Move).unwrap();
+        assert_eq!(
+            mem_files(&fs),
+            vec![
+                (tmp, String::from("other")),
+                (String::from("a"), String::from("a")),
+                (String::from("b"), String::from("b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn replace_requested_temp_like_name() {
+        let name = format!("{}foo", TEMP_PREFIX);
+        let fs = mem_fs(&["a", &name]);
+        let files = to_map::<PathBuf, PathBuf>(&[("a", &name)]);
+        let edges = build_renames(&files).unwrap();
+        let mut log = Log::default();
+
+        // Only names picked to break cycles are kept from replacing.
+        run_edges(&fs, &edges, Path::new(""), &mut log).unwrap();
+        assert_eq!(mem_files(&fs), vec![(name, String::from("a"))]);
+        assert_eq!(log.edges.len(), 1);
+        assert!(log.edges[0].error.is_none());
+    }
+
+    /// Returns a MemFs holding files, each containing its own name.
+    fn mem_fs(files: &[&str]) -> MemFs {
+        let fs = MemFs::new();
+        for file in files {
+            fs.write(file, file).unwrap();
+        }
+        fs
+    }
+
+    fn mem_files(fs: &MemFs) -> Vec<(String, String)> {
+        fs.files()
+            .into_iter()
+            .map(|(path, contents)| (path.to_str().unwrap().to_string(), contents))
+            .collect()
+    }
+
+    fn unchanged(files: &[&str]) -> Vec<(String, String)> {
+        files
+            .iter()
+            .map(|file| (file.to_string(), file.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn rename_in_memory() {
+        let fs = mem_fs(&["a", "b", "c"]);
+        let files = to_map::<PathBuf, PathBuf>(&[("a", "b"), ("b", "a"), ("c", "x/y/c")]);
+
+        rename_on(&fs, &files, None, &Options::default(), &mut Log::default()).unwrap();
+
+        assert_eq!(
+            mem_files(&fs),
+            vec![
+                (String::from("a"), String::from("b")),
+                (String::from("b"), String::from("a")),
+                (String::from("x/y/c"), String::from("c")),
+            ]
+        );
+    }
+
+    #[test]
+    fn undo_on_cross_device_error() {
+        let fs = mem_fs(&["a", "b", "c", "d"]);
+        fs.fail("c", libc::EXDEV);
+        let files = to_map::<PathBuf, PathBuf>(&[("a", "b"), ("b", "a"), ("c", "x/c"), ("d", "e")]);
+        let mut log = Log::default();
+
+        assert_eq!(
+            rename_on(&fs, &files, None, &Options::default(), &mut log),
+            Err(String::from("Invalid cross-device link (os error 18)"))
+        );
+        assert_eq!(mem_files(&fs), unchanged(&["a", "b", "c", "d"]));
+        // The swap ran before c failed and was undone.
+        assert_eq!(log.edges.len(), 4);
+        assert_eq!(log.rollback.len(), 3);
+        assert!(log.rollback.iter().all(|entry| entry.error.is_none()));
+    }
+
+    #[test]
+    fn undo_completed_renames() {
+        let fs = mem_fs(&["a", "b", "c"]);
+        let files = to_map::<PathBuf, PathBuf>(&[("a", "b"), ("b", "a"), ("c", "x/d")]);
+        let mut log = Log::default();
+
+        rename_on(&fs, &files, None, &Options::default(), &mut log).unwrap();
+        undo_on(&fs, &mut log, Mode::Move).unwrap();
+        assert_eq!(mem_files(&fs), unchanged(&["a", "b", "c"]));
+        assert_eq!(log.rollback.len(), log.edges.len());
+    }
+
+    #[test]
+    fn partial_rollback() {
+        // Edges are b -> c then a -> b. a fails, and so does moving c
+        // back, which leaves b at c.
+        let fs = mem_fs(&["a", "b"]);
+        fs.fail("a", libc::EXDEV);
+        fs.fail("c", libc::EIO);
+        let files = to_map::<PathBuf, PathBuf>(&[("a", "b"), ("b", "c")]);
+        let mut log = Log::default();
+
+        assert!(rename_on(&fs, &files, None, &Options::default(), &mut log).is_err());
+        assert_eq!(
+            mem_files(&fs),
+            vec![
+                (String::from("a"), String::from("a")),
+                (String::from("c"), String::from("b")),
+            ]
+        );
+        assert_eq!(log.rollback.len(), 1);
+        assert_eq!(
+            log.rollback[0].error,
+            Some(String::from("Input/output error (os error 5)"))
+        );
+    }
+
+    #[test]
+    fn parallel_undo_in_memory() {
+        let names = (0..50)
+            .map(|i| format!("f{:02}", i))
+            .collect::<Vec<String>>();
+        let names = names.iter().map(String::as_str).collect::<Vec<&str>>();
+        let fs = mem_fs(&names);
+        fs.fail("f25", libc::EXDEV);
+        let renamed = names
+            .iter()
+            .map(|name| format!("dir/{}", name))
+            .collect::<Vec<String>>();
+        let files = names
+            .iter()
+            .zip(&renamed)
+            .map(|(&src, dst)| (PathBuf::from(src), PathBuf::from(dst)))
+            .collect::<HashMap<PathBuf, PathBuf>>();
+        let options = Options {
+            jobs: 4,
+            ..Options::default()
+        };
+
+        assert!(rename_on(&fs, &files, None, &options, &mut Log::default()).is_err());
+        assert_eq!(mem_files(&fs), unchanged(&names));
+    }
+}
+
+#[cfg(test)]
+mod proptests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use proptest::prelude::*;
+
+    use super::super::filesystem::MemFs;
+    use super::{rename_on, Log, Options};
+
+    /// Sources and destinations of a random mapping: a permutation of
+    /// the sources, made of cycles, where some destinations are
+    /// replaced by new names, which turns their cycles into chains.
+    fn mapping() -> impl Strategy<Value = (Vec<String>, Vec<String>)> {
+        (1usize..16)
+            .prop_flat_map(|n| {
+                let names = (0..n)
+                    .map(|i| match i % 3 {
+                        2 => format!("d{}/f{}", i % 2, i),
+                        _ => format!("f{}", i),
+                    })
+                    .collect::<Vec<String>>();
+                (
+                    Just(names.clone()),
+                    Just(names).prop_shuffle(),
+                    proptest::collection::vec(any::<bool>(), n),
+                )
+            })
+            .prop_map(|(srcs, dsts, fresh)| {
+                let dsts = dsts
+                    .into_iter()
+                    .zip(fresh)
+                    .enumerate()
+                    .map(|(i, (dst, fresh))| if fresh { format!("new/{}", i) } else { dst })
+                    .collect();
+                (srcs, dsts)
+            })
+    }
+
+    fn setup(srcs: &[String], dsts: &[String]) -> (MemFs, HashMap<PathBuf, PathBuf>) {
+        let fs = MemFs::new();
+        for src in srcs {
+            fs.write(src, src).unwrap();
+        }
+        let files = srcs
+            .iter()
+            .zip(dsts)
+            .map(|(src, dst)| (PathBuf::from(src), PathBuf::from(dst)))
+            .collect();
+        (fs, files)
+    }
+
+    proptest! {
+        #[test]
+        fn rename_yields_mapping((srcs, dsts) in mapping(), jobs in 1usize..=4) {
+            let (fs, files) = setup(&srcs, &dsts);
+            let options = Options { jobs, ..Options::default() };
+
+            rename_on(&fs, &files, None, &options, &mut Log::default()).unwrap();
+
+            let mut expected = dsts
+                .iter()
+                .zip(&srcs)
+                .map(|(dst, src)| (PathBuf::from(dst), src.clone()))
+                .collect::<Vec<(PathBuf, String)>>();
+            expected.sort();
+            prop_assert_eq!(fs.files(), expected);
+        }
+
+        #[test]
+        fn reject_collisions(
+            (srcs, mut dsts) in mapping().prop_filter("needs two files", |(srcs, _)| srcs.len() > 1),
+            pick in any::<(prop::sample::Index, prop::sample::Index)>(),
+            jobs in 1usize..=4,
+        ) {
+            let i = pick.0.index(dsts.len());
+            let j = (i + 1 + pick.1.index(dsts.len() - 1)) % dsts.len();
+            dsts[j] = dsts[i].clone();
+            let (fs, files) = setup(&srcs, &dsts);
+            let before = fs.files();
+            let options = Options { jobs, ..Options::default() };
+
+            let err = rename_on(&fs, &files, None, &options, &mut Log::default()).unwrap_err();
+
+            prop_assert!(err.contains(&format!("Duplicate destination {}", dsts[i])), "{}", err);
+            prop_assert_eq!(fs.files(), before);
+        }
+    }
 }
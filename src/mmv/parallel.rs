@@ -0,0 +1,186 @@
+//! Concurrent execution of independent chains.
+//!
+//! Chains and cycles never share a path, but they can still depend on
+//! each other through directories, Eg. one chain renames `a` to `b`
+//! while another moves `a/x` elsewhere. Such chains are merged into a
+//! single group which runs serially, in the original order.
+//!
+//! Groups run on a bounded number of threads. A failing group undoes
+//! its own edges and stops new groups from starting; once running
+//! groups are done, every group which completed is undone as well.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use super::super::filesystem::Filesystem;
+use super::{undo_edges, Edge, Executor, Log};
+
+/// Splits edges into groups which can safely run concurrently.
+/// `starts` holds the index of the first edge of every chain.
+pub fn group_chains<'a>(edges: &'a [Edge], starts: &[usize]) -> Vec<Vec<&'a Edge>> {
+    let chain_of = |i: usize| match starts.binary_search(&i) {
+        Ok(chain) => chain,
+        Err(next) => next - 1,
+    };
+
+    // Chain owning each path. Temporary names belong to their cycle.
+    let mut owner = HashMap::<&Path, usize>::new();
+    for (i, edge) in edges.iter().enumerate() {
+        owner.insert(&edge.src, chain_of(i));
+        owner.insert(&edge.dst, chain_of(i));
+    }
+
+    // Union chains where a path of one is inside a path of another.
+    let mut parent = (0..starts.len()).collect::<Vec<usize>>();
+    for (path, &chain) in &owner {
+        for ancestor in path.ancestors().skip(1) {
+            if let Some(&other) = owner.get(ancestor) {
+                union(&mut parent, chain, other);
+            }
+        }
+    }
+
+    // Keep groups, and edges within them, in their original order.
+    let mut group_of_root = HashMap::<usize, usize>::new();
+    let mut groups = Vec::<Vec<&Edge>>::new();
+    for (i, edge) in edges.iter().enumerate() {
+        let root = find(&mut parent, chain_of(i));
+        let group = *group_of_root.entry(root).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(edge);
+    }
+
+    groups
+}
+
+/// Runs groups on fs with executor, on up to `jobs` threads.
+pub fn execute(
+    fs: &dyn Filesystem,
+    groups: &[Vec<&Edge>],
+    dir: &Path,
+    jobs: usize,
+    executor: &dyn Executor,
+    log: &mut Log,
+) -> Result<(), String> {
+    if jobs == 1 {
+        return executor.run(fs, groups, dir, log);
+    }
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    // (group, its log, its result) in order of completion.
+    let done = Mutex::new(Vec::<(usize, Log, Result<(), String>)>::new());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(groups.len()) {
+            scope.spawn(|| {
+                while !failed.load(Ordering::SeqCst) {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= groups.len() {
+                        break;
+                    }
+
+                    let mut group_log = Log::default();
+                    let res = executor.run(fs, &groups[i..=i], dir, &mut group_log);
+                    if res.is_err() {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    done.lock().unwrap().push((i, group_log, res));
+                }
+            });
+        }
+    });
+
+    let done = done.into_inner().unwrap();
+    let mut errors = Vec::<String>::new();
+    let mut completed = Vec::<usize>::new();
+    for (i, group_log, res) in done {
+        log.edges.extend(group_log.edges);
+        log.rollback.extend(group_log.rollback);
+        // Needed to undo completed groups below.
+        log.temp_names.extend(group_log.temp_names);
+        match res {
+            Ok(()) => completed.push(i),
+            Err(err) => errors.push(err),
+        }
+    }
+    // Interleave the logs of all threads in the order edges ran.
+    log.edges.sort_by_key(|entry| entry.started);
+    log.rollback.sort_by_key(|entry| entry.started);
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    // Failed groups already undid their own edges.
+    for &i in completed.iter().rev() {
+        undo_edges(fs, &groups[i], dir, log);
+    }
+
+    Err(errors.join("\n"))
+}
+
+fn find(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+fn union(parent: &mut [usize], a: usize, b: usize) {
+    let (a, b) = (find(parent, a), find(parent, b));
+    // The smaller root wins, so the result does not depend on the
+    // order of unions.
+    if a < b {
+        parent[b] = a;
+    } else {
+        parent[a] = b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::super::{Edge, Hop};
+    use super::group_chains;
+
+    fn edge(src: &str, dst: &str) -> Edge {
+        Edge {
+            src: PathBuf::from(src),
+            dst: PathBuf::from(dst),
+            hop: Hop::Direct,
+        }
+    }
+
+    #[test]
+    fn group_nested_chains() {
+        let edges = [
+            // Chain 0: a -> b
+            edge("a", "b"),
+            // Chain 1: c -> d, independent
+            edge("c", "d"),
+            // Chain 2: moves a file out of a
+            edge("a/x", "e"),
+            // Chain 3: moves a file into d
+            edge("f", "d/y"),
+        ];
+        let groups = group_chains(&edges, &[0, 1, 2, 3])
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|edge| edge.src.to_str().unwrap())
+                    .collect::<Vec<&str>>()
+            })
+            .collect::<Vec<Vec<&str>>>();
+
+        assert_eq!(groups, vec![vec!["a", "a/x"], vec!["c", "f"]]);
+    }
+}
@@ -0,0 +1,485 @@
+//! io_uring backend, Linux only.
+//!
+//! Each group is submitted as a chain of linked `renameat` entries, so
+//! the kernel runs its edges in order while other groups proceed
+//! independently. Thousands of renames then cost a handful of system
+//! calls instead of one (or three, on failure) per edge.
+//!
+//! When a rename fails, the entries linked after it may either be
+//! cancelled or run anyway, and both are handled. Cancelled entries
+//! complete with `ECANCELED` without running; those were not
+//! attempted, so they are not logged and simply wait for the next
+//! submission. So that an entry which does run never replaces a path
+//! which failed to move away, hops into a path vacated by the previous
+//! edge use `RENAME_NOREPLACE`, as do hops to temporary names. Once a
+//! submission is done, its first failed edge is retried like the std
+//! backend does, which creates missing destination directories, and
+//! the edges after it which did not run or failed are submitted again.
+//!
+//! Renames submitted to the ring always act on the operating system,
+//! only retries and rollback go through the given `Filesystem`.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use io_uring::{opcode, squeue, types, IoUring, Probe};
+
+use super::super::filesystem::Filesystem;
+use super::{run_edge, undo_edges, Edge, Executor, Hop, Log, LogEntry};
+
+/// Submission queue size of every ring.
+const RING_ENTRIES: u32 = 256;
+
+thread_local! {
+    // Rings are per thread, so that parallel groups never share one.
+    static RING: RefCell<Option<IoUring>> = const { RefCell::new(None) };
+}
+
+pub struct UringExecutor;
+
+impl UringExecutor {
+    /// Returns None if the kernel cannot rename through io_uring
+    /// (before 5.11, or io_uring is disabled).
+    pub fn new() -> Option<Self> {
+        let ring = IoUring::new(RING_ENTRIES).ok()?;
+        let mut probe = Probe::new();
+        ring.submitter().register_probe(&mut probe).ok()?;
+        if !probe.is_supported(opcode::RenameAt::CODE) {
+            return None;
+        }
+
+        RING.with(|cell| *cell.borrow_mut() = Some(ring));
+        Some(UringExecutor)
+    }
+}
+
+impl Executor for UringExecutor {
+    fn run(
+        &self,
+        fs: &dyn Filesystem,
+        groups: &[Vec<&Edge>],
+        dir: &Path,
+        log: &mut Log,
+    ) -> Result<(), String> {
+        RING.with(|cell| {
+            let mut cell = cell.borrow_mut();
+            if cell.is_none() {
+                *cell = Some(IoUring::new(RING_ENTRIES).map_err(|err| err.to_string())?);
+            }
+            run_ring(cell.as_mut().unwrap(), fs, groups, dir, log)
+        })
+    }
+}
+
+/// Progress of one group.
+struct Chain {
+    /// Edges not submitted yet, in order.
+    todo: VecDeque<usize>,
+    /// Edges of the last submission.
+    submitted: Vec<usize>,
+    /// Number of submitted edges not completed yet.
+    pending: usize,
+    submitted_at: SystemTime,
+    done: Vec<bool>,
+}
+
+fn run_ring(
+    ring: &mut IoUring,
+    fs: &dyn Filesystem,
+    groups: &[Vec<&Edge>],
+    dir: &Path,
+    log: &mut Log,
+) -> Result<(), String> {
+    let capacity = ring.params().sq_entries() as usize;
+    let mut chains = groups
+        .iter()
+        .map(|group| Chain {
+            todo: (0..group.len()).collect(),
+            submitted: Vec::new(),
+            pending: 0,
+            submitted_at: SystemTime::now(),
+            done: vec![false; group.len()],
+        })
+        .collect::<Vec<Chain>>();
+    // Groups waiting for their next submission.
+    let mut ready = (0..groups.len()).collect::<VecDeque<usize>>();
+    // Paths of the chain being submitted.
+    let mut paths = Vec::<(CString, CString)>::new();
+    let mut in_flight = 0;
+    let mut error = None::<String>;
+
+    loop {
+        // Submit as much of every ready chain as fits. A chain cannot
+        // span submissions, so a long one is split and its next part
+        // waits for the previous one.
+        while error.is_none() && in_flight < capacity {
+            let g = match ready.pop_front() {
+                Some(g) => g,
+                None => break,
+            };
+            let (group, chain) = (&groups[g], &mut chains[g]);
+            let count = chain.todo.len().min(capacity - in_flight);
+            chain.submitted = chain.todo.drain(..count).collect();
+            paths.clear();
+            for &i in &chain.submitted {
+                match c_paths(group[i], dir, log) {
+                    Ok(c_paths) => paths.push(c_paths),
+                    Err(err) => error = Some(err),
+                }
+            }
+            if error.is_some() {
+                break;
+            }
+
+            let mut queue = ring.submission();
+            for (n, (&i, (src, dst))) in chain.submitted.iter().zip(&paths).enumerate() {
+                // The previous edge moved dst away, unless it failed.
+                let vacated = i > 0 && group[i - 1].src == group[i].dst;
+                let noreplace = vacated || group[i].hop == Hop::ToTemp;
+                let mut entry = opcode::RenameAt::new(
+                    types::Fd(libc::AT_FDCWD),
+                    src.as_ptr(),
+                    types::Fd(libc::AT_FDCWD),
+                    dst.as_ptr(),
+                )
+                .flags(if noreplace { libc::RENAME_NOREPLACE } else { 0 })
+                .build()
+                .user_data(user_data(g, i));
+                if n + 1 < count {
+                    entry = entry.flags(squeue::Flags::IO_LINK);
+                }
+                // SAFETY: the queue has room for every edge in flight,
+                // and the paths live until the submission below.
+                unsafe { queue.push(&entry) }.expect("submission queue is full");
+            }
+            drop(queue);
+            // The kernel copies paths when entries are submitted, so
+            // submit before they are dropped.
+            if let Err(err) = ring.submit() {
+                error = Some(err.to_string());
+                break;
+            }
+
+            chain.pending = count;
+            chain.submitted_at = SystemTime::now();
+            in_flight += count;
+        }
+
+        if in_flight == 0 {
+            break;
+        }
+        if let Err(err) = ring.submit_and_wait(1) {
+            // Completions can no longer be trusted, undo what is known
+            // to be done.
+            error.get_or_insert(err.to_string());
+            break;
+        }
+
+        let completed = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect::<Vec<(u64, i32)>>();
+        for (data, res) in completed {
+            let (g, i) = ((data >> 32) as usize, (data & u32::MAX as u64) as usize);
+            let (group, chain) = (&groups[g], &mut chains[g]);
+            in_flight -= 1;
+            chain.pending -= 1;
+
+            // Cancelled after an earlier edge of the chain failed, it
+            // was never attempted.
+            if res != -libc::ECANCELED {
+                let (src, dst) = (
+                    log.temp_name(&dir.join(&group[i].src)),
+                    log.temp_name(&dir.join(&group[i].dst)),
+                );
+                let err = if res < 0 {
+                    Some(io::Error::from_raw_os_error(-res))
+                } else {
+                    chain.done[i] = true;
+                    None
+                };
+                push_entry(log, src, dst, chain.submitted_at, err);
+            }
+
+            if chain.pending > 0 {
+                continue;
+            }
+            let mut failed = chain
+                .submitted
+                .iter()
+                .copied()
+                .filter(|&i| !chain.done[i])
+                .collect::<VecDeque<usize>>();
+            if let Some(i) = failed.pop_front() {
+                // Every edge before this one is done, so retrying it
+                // is the same as running it on the std backend.
+                if let Err(err) = run_edge(fs, group[i], dir, log) {
+                    error.get_or_insert(err.to_string());
+                    continue;
+                }
+                chain.done[i] = true;
+                // Edges which failed after it run again.
+                while let Some(i) = failed.pop_back() {
+                    chain.todo.push_front(i);
+                }
+            }
+            if !chain.todo.is_empty() {
+                ready.push_back(g);
+            }
+        }
+    }
+
+    match error {
+        None => Ok(()),
+        Some(err) => {
+            for (group, chain) in groups.iter().zip(&chains).rev() {
+                let done = group
+                    .iter()
+                    .zip(&chain.done)
+                    .filter(|&(_, &done)| done)
+                    .map(|(&edge, _)| edge)
+                    .collect::<Vec<&Edge>>();
+                undo_edges(fs, &done, dir, log);
+            }
+            Err(err)
+        }
+    }
+}
+
+fn user_data(group: usize, edge: usize) -> u64 {
+    ((group as u64) << 32) | edge as u64
+}
+
+fn c_paths(edge: &Edge, dir: &Path, log: &Log) -> Result<(CString, CString), String> {
+    let c_path = |path: &Path| {
+        let path = log.temp_name(&dir.join(path));
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| format!("Path contains a nul byte {}", path.display()))
+    };
+    Ok((c_path(&edge.src)?, c_path(&edge.dst)?))
+}
+
+fn push_entry(
+    log: &mut Log,
+    src: PathBuf,
+    dst: PathBuf,
+    started: SystemTime,
+    error: Option<io::Error>,
+) {
+    log.edges.push(LogEntry {
+        src,
+        dst: Some(dst),
+        started,
+        finished: SystemTime::now(),
+        error: error.map(|err| err.to_string()),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::super::super::filesystem::OsFs;
+    use super::super::super::ioutils::temp_dir;
+    use super::super::{edges, Edge, Executor, Hop, Log};
+    use super::UringExecutor;
+
+    fn edge(src: &str, dst: &str) -> Edge {
+        Edge {
+            src: PathBuf::from(src),
+            dst: PathBuf::from(dst),
+            hop: Hop::Direct,
+        }
+    }
+
+    /// Runs groups of edges in a directory holding `files`, which
+    /// contain their own name. Returns the result and every file with
+    /// its contents afterwards.
+    fn run(files: &[String], groups: &[Vec<Edge>]) -> (Result<(), String>, Vec<(String, String)>) {
+        let dir = PathBuf::from(temp_dir("", "mmv-uring-").unwrap());
+        for file in files {
+            fs::write(dir.join(file), file).unwrap();
+        }
+
+        let groups = groups
+            .iter()
+            .map(|group| group.iter().collect::<Vec<&Edge>>())
+            .collect::<Vec<Vec<&Edge>>>();
+        let res = UringExecutor.run(&OsFs, &groups, &dir, &mut Log::default());
+
+        let mut got = Vec::<(String, String)>::new();
+        list(&dir, &dir, &mut got);
+        got.sort();
+        fs::remove_dir_all(dir).unwrap();
+
+        (res, got)
+    }
+
+    fn list(root: &Path, dir: &Path, out: &mut Vec<(String, String)>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                list(root, &path, out);
+            } else {
+                let name = path.strip_prefix(root).unwrap().to_str().unwrap();
+                out.push((name.to_string(), fs::read_to_string(&path).unwrap()));
+            }
+        }
+    }
+
+    #[test]
+    fn rename_groups() {
+        if UringExecutor::new().is_none() {
+            // io_uring renames are not available here.
+            return;
+        }
+
+        // More edges than fit in a ring, into a missing directory.
+        let names = (0..300).map(|i| format!("a{}", i)).collect::<Vec<String>>();
+        let moves = names
+            .iter()
+            .map(|name| edge(name, &format!("x/{}", name)))
+            .collect::<Vec<Edge>>();
+        // A chain, b1 -> b2 must run first.
+        let chain = vec![edge("b1", "b2"), edge("b0", "b1")];
+        let mut files = names.clone();
+        files.extend(vec![String::from("b0"), String::from("b1")]);
+
+        let (res, got) = run(&files, &[moves, chain]);
+        assert_eq!(res, Ok(()));
+
+        let mut expected = names
+            .iter()
+            .map(|name| (format!("x/{}", name), name.clone()))
+            .collect::<Vec<(String, String)>>();
+        expected.push((String::from("b1"), String::from("b0")));
+        expected.push((String::from("b2"), String::from("b1")));
+        expected.sort();
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn undo_groups_on_error() {
+        if UringExecutor::new().is_none() {
+            return;
+        }
+
+        let files = ["a", "b", "c"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<String>>();
+        let groups = [
+            vec![edge("a", "x/a")],
+            vec![edge("b", "y"), edge("missing", "z"), edge("c", "w")],
+        ];
+
+        let (res, got) = run(&files, &groups);
+        assert_eq!(
+            res,
+            Err(String::from("No such file or directory (os error 2)"))
+        );
+        assert_eq!(
+            got,
+            files
+                .iter()
+                .map(|name| (name.clone(), name.clone()))
+                .collect::<Vec<(String, String)>>()
+        );
+    }
+
+    #[test]
+    fn skip_cancelled_edges() {
+        if UringExecutor::new().is_none() {
+            return;
+        }
+
+        let dir = PathBuf::from(temp_dir("", "mmv-uring-").unwrap());
+        for file in &["a", "c"] {
+            fs::write(dir.join(file), file).unwrap();
+        }
+        let group = [edge("a", "b"), edge("missing", "z"), edge("c", "d")];
+        let groups = [group.iter().collect::<Vec<&Edge>>()];
+        let mut log = Log::default();
+
+        assert!(UringExecutor.run(&OsFs, &groups, &dir, &mut log).is_err());
+        // Whether or not the kernel cancelled c -> d after missing
+        // failed, no cancelled edge is logged as failed.
+        let failed = log
+            .edges
+            .iter()
+            .filter(|entry| entry.error.is_some())
+            .map(|entry| entry.src.strip_prefix(&dir).unwrap().to_path_buf())
+            .collect::<Vec<PathBuf>>();
+        // Once in the ring, once when retried.
+        assert_eq!(failed, vec![PathBuf::from("missing"); 2]);
+        let done = log.edges.iter().filter(|entry| entry.error.is_none());
+        assert_eq!(done.count(), log.rollback.len());
+        assert_eq!(fs::read_to_string(dir.join("a")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dir.join("c")).unwrap(), "c");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replace_taken_temp_name() {
+        if UringExecutor::new().is_none() {
+            return;
+        }
+
+        let dir = PathBuf::from(temp_dir("", "mmv-uring-").unwrap());
+        for file in &["a", "b"] {
+            fs::write(dir.join(file), file).unwrap();
+        }
+        let files = [
+            (dir.join("a"), dir.join("b")),
+            (dir.join("b"), dir.join("a")),
+        ]
+        .iter()
+        .cloned()
+        .collect::<HashMap<PathBuf, PathBuf>>();
+        let edges = edges(&files).unwrap();
+        // Someone else took the name after it was picked.
+        fs::write(&edges[0].dst, "other").unwrap();
+
+        let groups = [edges.iter().collect::<Vec<&Edge>>()];
+        let res = UringExecutor.run(&OsFs, &groups, Path::new(""), &mut Log::default());
+        assert_eq!(res, Ok(()));
+        assert_eq!(fs::read_to_string(dir.join("a")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(dir.join("b")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(&edges[0].dst).unwrap(), "other");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn never_replace_after_failure() {
+        if UringExecutor::new().is_none() {
+            return;
+        }
+
+        // d is a file, so b1 cannot move and b0 must not replace it.
+        let files = ["b0", "b1", "d"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<String>>();
+        let chain = vec![edge("b1", "d/b2"), edge("b0", "b1")];
+
+        let (res, got) = run(&files, &[chain]);
+        assert_eq!(res, Err(String::from("File exists (os error 17)")));
+        assert_eq!(
+            got,
+            files
+                .iter()
+                .map(|name| (name.clone(), name.clone()))
+                .collect::<Vec<(String, String)>>()
+        );
+    }
+}
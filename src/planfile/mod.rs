@@ -0,0 +1,171 @@
+//! Plans exported by `mmv plan` and executed later by `mmv apply`.
+//!
+//! A plan file holds the validated edits, with conflict policies
+//! already applied, and the fingerprint of every path involved. The
+//! fingerprints are checked again before applying, so a plan is only
+//! executed against the exact files it was reviewed for.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::mmv::Mode;
+use super::snapshot::Snapshot;
+
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanFile {
+    pub version: u32,
+    /// Directory relative paths are resolved against.
+    pub directory: PathBuf,
+    pub mode: Mode,
+    /// Sorted by source.
+    pub renames: Vec<Mapping>,
+    /// Existing destinations which `renames` moves to a backup name.
+    #[serde(default)]
+    pub backups: Vec<PathBuf>,
+    pub trash: Vec<PathBuf>,
+    pub create: Vec<PathBuf>,
+    /// State of every source and destination when planning, null for
+    /// paths which did not exist.
+    pub fingerprints: Snapshot,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Mapping {
+    pub src: PathBuf,
+    pub dst: PathBuf,
+}
+
+impl PlanFile {
+    pub fn new(
+        mode: Mode,
+        renames: &HashMap<PathBuf, PathBuf>,
+        backups: &[PathBuf],
+        trash: &[PathBuf],
+        create: &[PathBuf],
+        fingerprints: Snapshot,
+    ) -> Result<Self, String> {
+        let mut renames = renames
+            .iter()
+            .map(|(src, dst)| Mapping {
+                src: src.clone(),
+                dst: dst.clone(),
+            })
+            .collect::<Vec<Mapping>>();
+        renames.sort_by(|a, b| a.src.cmp(&b.src));
+
+        Ok(PlanFile {
+            version: VERSION,
+            directory: current_dir()?,
+            mode,
+            renames,
+            backups: backups.to_vec(),
+            trash: trash.to_vec(),
+            create: create.to_vec(),
+            fingerprints,
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let mut json = serde_json::to_string_pretty(self).map_err(|err| err.to_string())?;
+        json.push('\n');
+        fs::write(path, json)
+            .map_err(|err| format!("Error writing plan {}:\n{}", path.display(), err))
+    }
+
+    /// Reads a plan, refusing plans of another version or made in
+    /// another directory.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Error reading plan {}:\n{}", path.display(), err))?;
+        let plan = serde_json::from_str::<PlanFile>(&contents)
+            .map_err(|err| format!("Invalid plan {}:\n{}", path.display(), err))?;
+
+        if plan.version != VERSION {
+            return Err(format!(
+                "Unsupported plan version {} in {}",
+                plan.version,
+                path.display()
+            ));
+        }
+        if plan.directory != current_dir()? {
+            return Err(format!(
+                "Plan was made in {}, apply it from there.",
+                plan.directory.display()
+            ));
+        }
+
+        Ok(plan)
+    }
+
+    pub fn rename_map(&self) -> HashMap<PathBuf, PathBuf> {
+        self.renames
+            .iter()
+            .map(|mapping| (mapping.src.clone(), mapping.dst.clone()))
+            .collect()
+    }
+}
+
+fn current_dir() -> Result<PathBuf, String> {
+    env::current_dir()
+        .and_then(fs::canonicalize)
+        .map_err(|err| format!("Error reading current directory:\n{}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::super::ioutils::temp_dir;
+    use super::super::mmv::Mode;
+    use super::super::snapshot::Snapshot;
+    use super::PlanFile;
+
+    #[test]
+    fn write_and_read() {
+        let dir = PathBuf::from(temp_dir("", "mmv-planfile-").unwrap());
+        fs::write(dir.join("foo"), "").unwrap();
+
+        let renames = [(dir.join("foo"), dir.join("bar"))]
+            .iter()
+            .cloned()
+            .collect::<HashMap<PathBuf, PathBuf>>();
+        let mut snapshot = Snapshot::new();
+        snapshot.add(dir.join("foo"));
+        snapshot.add(dir.join("bar"));
+
+        let path = dir.join("plan.json");
+        let plan =
+            PlanFile::new(Mode::Copy, &renames, &[], &[], &[dir.join("new")], snapshot).unwrap();
+        plan.write(&path).unwrap();
+
+        let read = PlanFile::read(&path).unwrap();
+        assert_eq!(read.mode, Mode::Copy);
+        assert_eq!(read.rename_map(), renames);
+        assert_eq!(read.create, vec![dir.join("new")]);
+        assert!(read.fingerprints.changes().is_empty());
+
+        fs::remove_file(dir.join("foo")).unwrap();
+        assert_eq!(
+            read.fingerprints.changes(),
+            vec![format!("{} was deleted", dir.join("foo").display())]
+        );
+
+        let json = fs::read_to_string(&path)
+            .unwrap()
+            .replace("\"version\": 1", "\"version\": 99");
+        fs::write(&path, json).unwrap();
+        assert!(PlanFile::read(&path)
+            .unwrap_err()
+            .starts_with("Unsupported plan version 99"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
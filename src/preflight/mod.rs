@@ -0,0 +1,332 @@
+//! Pre-flight checks run before anything is moved.
+//!
+//! Without these checks, a problem such as a missing permission
+//! only surfaces in the middle of execution and triggers a rollback.
+//! Instead, every source and destination is checked up front and
+//! all problems are reported at once.
+
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use super::filepath::clean;
+
+/// Used when pathconf() cannot tell the limits.
+const DEFAULT_NAME_MAX: usize = 255;
+const DEFAULT_PATH_MAX: usize = 4096;
+
+/// S_ISVTX, whose type differs between platforms.
+const STICKY_BIT: u32 = 0o1000;
+
+/// Checks that every rename in `files`, every path in `trash` and
+/// every directory in `create` can be done. `keep_sources` is set
+/// when sources are copied or linked, so their directories are not
+/// modified.
+///
+/// Returns a list of problems, which is empty when all checks pass.
+pub fn check<P: AsRef<Path>>(
+    files: &HashMap<P, P>,
+    trash: &[PathBuf],
+    create: &[PathBuf],
+    keep_sources: bool,
+) -> Vec<String> {
+    let mut problems = BTreeSet::<String>::new();
+
+    for (src, dst) in files {
+        let (src, dst) = (clean(src), clean(dst));
+        if src == dst {
+            continue;
+        }
+
+        if !keep_sources {
+            check_source(&src, &mut problems);
+        } else if fs::symlink_metadata(&src).is_err() {
+            problems.insert(format!("Source does not exist {}", src.display()));
+        }
+        check_destination(&dst, &mut problems);
+    }
+
+    for path in trash {
+        check_source(&clean(path), &mut problems);
+    }
+
+    for dir in create {
+        let dir = clean(dir);
+        match fs::metadata(&dir) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => {
+                problems.insert(format!("Not a directory {}", dir.display()));
+            }
+            Err(_err) => check_destination(&dir, &mut problems),
+        }
+    }
+
+    problems.into_iter().collect()
+}
+
+/// Checks that `src` exists and can be removed from its directory.
+fn check_source(src: &Path, problems: &mut BTreeSet<String>) {
+    let metadata = match fs::symlink_metadata(src) {
+        Ok(metadata) => metadata,
+        Err(_err) => {
+            problems.insert(format!("Source does not exist {}", src.display()));
+            return;
+        }
+    };
+
+    let parent = parent_dir(src);
+    check_writable_dir(&parent, problems);
+
+    if let Ok(dir_metadata) = fs::metadata(&parent) {
+        // SAFETY: geteuid() cannot fail and has no side effects.
+        let euid = unsafe { libc::geteuid() };
+        if !sticky_allows(
+            dir_metadata.mode(),
+            dir_metadata.uid(),
+            metadata.uid(),
+            euid,
+        ) {
+            problems.insert(format!(
+                "Sticky directory {} does not allow moving {}",
+                parent.display(),
+                src.display()
+            ));
+        }
+    }
+}
+
+/// In sticky directories (Eg. /tmp), only the owner of a file or the
+/// directory, or root, can rename it.
+fn sticky_allows(dir_mode: u32, dir_uid: u32, file_uid: u32, euid: u32) -> bool {
+    dir_mode & STICKY_BIT == 0 || euid == 0 || euid == file_uid || euid == dir_uid
+}
+
+/// Checks that `dst` can be created and its name is not too long.
+fn check_destination(dst: &Path, problems: &mut BTreeSet<String>) {
+    // Missing directories are created, so check the closest one
+    // which exists.
+    let mut dir = parent_dir(dst);
+    while fs::metadata(&dir).is_err() {
+        match dir.parent() {
+            Some(parent) if parent != Path::new("") => dir = parent.to_path_buf(),
+            _ => {
+                dir = PathBuf::from(".");
+                break;
+            }
+        }
+    }
+    check_writable_dir(&dir, problems);
+
+    let name_max = path_conf(&dir, libc::_PC_NAME_MAX).unwrap_or(DEFAULT_NAME_MAX);
+    for component in dst.iter() {
+        if component.len() > name_max {
+            problems.insert(format!(
+                "Name longer than {} bytes {}",
+                name_max,
+                dst.display()
+            ));
+        }
+    }
+
+    let path_max = path_conf(&dir, libc::_PC_PATH_MAX).unwrap_or(DEFAULT_PATH_MAX);
+    let absolute_len = match fs::canonicalize(&dir) {
+        Ok(abs_dir) if dst.is_relative() => abs_dir.as_os_str().len() + 1 + dst.as_os_str().len(),
+        _ => dst.as_os_str().len(),
+    };
+    if absolute_len >= path_max {
+        problems.insert(format!(
+            "Path longer than {} bytes {}",
+            path_max,
+            dst.display()
+        ));
+    }
+}
+
+/// Checks that entries can be added to or removed from `dir`.
+fn check_writable_dir(dir: &Path, problems: &mut BTreeSet<String>) {
+    let c_dir = match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(c_dir) => c_dir,
+        Err(_err) => return,
+    };
+
+    // SAFETY: c_dir is a valid NUL terminated string and stat is
+    // only read after statvfs() succeeds.
+    let read_only = unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        libc::statvfs(c_dir.as_ptr(), &mut stat) == 0 && stat.f_flag & libc::ST_RDONLY != 0
+    };
+    // SAFETY: c_dir is a valid NUL terminated string.
+    let access = match unsafe { libc::access(c_dir.as_ptr(), libc::W_OK | libc::X_OK) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    };
+
+    if let Some(problem) = writable_problem(dir, read_only, access) {
+        problems.insert(problem);
+    }
+}
+
+/// Returns why entries cannot be added to or removed from `dir`, given
+/// whether its file system is read-only and the result of access().
+fn writable_problem(dir: &Path, read_only: bool, access: io::Result<()>) -> Option<String> {
+    if read_only {
+        return Some(format!("Read-only file system {}", dir.display()));
+    }
+
+    access
+        .err()
+        .map(|err| format!("Cannot write to directory {}: {}", dir.display(), err))
+}
+
+fn path_conf(dir: &Path, name: libc::c_int) -> Option<usize> {
+    let c_dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    // SAFETY: c_dir is a valid NUL terminated string.
+    let value = unsafe { libc::pathconf(c_dir.as_ptr(), name) };
+    if value > 0 {
+        Some(value as usize)
+    } else {
+        None
+    }
+}
+
+/// Returns the directory containing `path`, `.` for relative names.
+fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    use super::super::ioutils::temp_dir;
+    use super::{check, sticky_allows, writable_problem};
+
+    #[test]
+    fn report_all_problems() {
+        let dir = PathBuf::from(temp_dir("", "mmv-preflight-").unwrap());
+        fs::write(dir.join("foo"), "0").unwrap();
+        let long_name = "x".repeat(300);
+
+        let mut files = HashMap::<PathBuf, PathBuf>::new();
+        files.insert(dir.join("foo"), dir.join("a/b/bar"));
+        files.insert(dir.join("missing"), dir.join("baz"));
+        files.insert(dir.join("other"), dir.join(&long_name));
+        let trash = vec![dir.join("gone")];
+
+        let problems = check(&files, &trash, &[], false);
+        assert_eq!(
+            problems,
+            vec![
+                format!(
+                    "Name longer than 255 bytes {}",
+                    dir.join(&long_name).display()
+                ),
+                format!("Source does not exist {}", dir.join("gone").display()),
+                format!("Source does not exist {}", dir.join("missing").display()),
+                format!("Source does not exist {}", dir.join("other").display()),
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn check_created_directories() {
+        let dir = PathBuf::from(temp_dir("", "mmv-preflight-").unwrap());
+        fs::create_dir(dir.join("exists")).unwrap();
+        fs::write(dir.join("file"), "0").unwrap();
+
+        let create = vec![
+            dir.join("exists/"),
+            dir.join("file/"),
+            dir.join("new/dir/"),
+            dir.join("x".repeat(300)),
+        ];
+        let problems = check(&HashMap::<PathBuf, PathBuf>::new(), &[], &create, false);
+        assert_eq!(
+            problems,
+            vec![
+                format!(
+                    "Name longer than 255 bytes {}",
+                    dir.join("x".repeat(300)).display()
+                ),
+                format!("Not a directory {}", dir.join("file").display()),
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_only_directory() {
+        let dir = PathBuf::from(temp_dir("", "mmv-preflight-").unwrap());
+        fs::write(dir.join("foo"), "0").unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+
+        let mut files = HashMap::<PathBuf, PathBuf>::new();
+        files.insert(dir.join("foo"), dir.join("bar"));
+        let problems = check(&files, &[], &[], false);
+        // SAFETY: geteuid() cannot fail and has no side effects.
+        if unsafe { libc::geteuid() } == 0 {
+            // Permissions do not apply to root.
+            assert!(problems.is_empty());
+        } else {
+            assert_eq!(
+                problems,
+                vec![format!(
+                    "Cannot write to directory {}: Permission denied (os error 13)",
+                    dir.display()
+                )]
+            );
+        }
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn writable_problems() {
+        let dir = Path::new("dir");
+        let denied = || Err(io::Error::from_raw_os_error(libc::EACCES));
+
+        assert_eq!(writable_problem(dir, false, Ok(())), None);
+        assert_eq!(
+            writable_problem(dir, true, Ok(())),
+            Some(String::from("Read-only file system dir"))
+        );
+        // A read-only file system is the more useful explanation.
+        assert_eq!(
+            writable_problem(dir, true, denied()),
+            Some(String::from("Read-only file system dir"))
+        );
+        assert_eq!(
+            writable_problem(dir, false, denied()),
+            Some(String::from(
+                "Cannot write to directory dir: Permission denied (os error 13)"
+            ))
+        );
+    }
+
+    #[test]
+    fn sticky_directories() {
+        let (sticky, plain) = (0o41777, 0o40777);
+
+        assert!(sticky_allows(plain, 1, 2, 3));
+        // Only root and the owners of the file or directory.
+        assert!(!sticky_allows(sticky, 1, 2, 3));
+        assert!(sticky_allows(sticky, 1, 2, 0));
+        assert!(sticky_allows(sticky, 1, 2, 1));
+        assert!(sticky_allows(sticky, 1, 2, 2));
+    }
+}
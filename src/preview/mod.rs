@@ -0,0 +1,248 @@
+//! Summary of planned changes shown before anything is executed.
+//!
+//! Only entries which change are listed. For renames, the part of
+//! the path which differs is highlighted, and files moved between
+//! the same two directories are grouped under a single heading.
+//! Colors are disabled when `NO_COLOR` is set or stdout is not a
+//! terminal (See https://no-color.org).
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use super::filepath::clean;
+
+static RED: &str = "\x1b[31m";
+static GREEN: &str = "\x1b[32m";
+static BOLD: &str = "\x1b[1m";
+static RESET: &str = "\x1b[0m";
+
+#[derive(Debug, PartialEq)]
+pub enum Answer {
+    Yes,
+    No,
+    Edit,
+}
+
+/// Returns true if the summary should be colored when printed to
+/// stderr, or stdout if `stderr` is false.
+pub fn use_color(stderr: bool) -> bool {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let terminal = if stderr {
+        io::stderr().is_terminal()
+    } else {
+        io::stdout().is_terminal()
+    };
+    !no_color && terminal
+}
+
+/// Returns true if the user can be asked for confirmation.
+pub fn can_prompt() -> bool {
+    io::stdin().is_terminal()
+}
+
+/// Returns a summary of the planned changes.
+pub fn summary<P: AsRef<Path>>(
+    renames: &[(P, P)],
+    trash: &[PathBuf],
+    create: &[PathBuf],
+    color: bool,
+) -> String {
+    let paint = |style: &str, text: &str| {
+        if color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    };
+
+    let mut changed = renames
+        .iter()
+        .map(|(src, dst)| (clean(src), clean(dst)))
+        .filter(|(src, dst)| src != dst)
+        .collect::<Vec<(PathBuf, PathBuf)>>();
+    changed.sort();
+
+    // Group files which only change directory by (old dir, new dir).
+    let mut moves = BTreeMap::<(PathBuf, PathBuf), Vec<PathBuf>>::new();
+    for (src, dst) in &changed {
+        if src.file_name() == dst.file_name() && src.parent() != dst.parent() {
+            let key = (parent_of(src), parent_of(dst));
+            moves.entry(key).or_default().push(src.clone());
+        }
+    }
+    moves.retain(|_, names| names.len() > 1);
+
+    let mut out = String::new();
+    let mut single = Vec::<String>::new();
+    for (src, dst) in &changed {
+        let key = (parent_of(src), parent_of(dst));
+        if src.file_name() == dst.file_name() && moves.contains_key(&key) {
+            continue;
+        }
+
+        let (src_str, dst_str) = (src.to_string_lossy(), dst.to_string_lossy());
+        let (prefix, old, new, suffix) = split_difference(&src_str, &dst_str);
+        single.push(format!(
+            "  {}{}{} -> {}{}{}\n",
+            prefix,
+            paint(RED, old),
+            suffix,
+            prefix,
+            paint(GREEN, new),
+            suffix
+        ));
+    }
+
+    if !single.is_empty() {
+        out.push_str(&paint(BOLD, "Rename:"));
+        out.push('\n');
+        single.iter().for_each(|line| out.push_str(line));
+    }
+
+    for ((src_dir, dst_dir), names) in &moves {
+        out.push_str(&paint(
+            BOLD,
+            &format!(
+                "Move from {}/ to {}/:",
+                src_dir.display(),
+                dst_dir.display()
+            ),
+        ));
+        out.push('\n');
+        for name in names {
+            let name = name.file_name().unwrap_or_default().to_string_lossy();
+            out.push_str(&format!("  {}\n", name));
+        }
+    }
+
+    if !trash.is_empty() {
+        out.push_str(&paint(BOLD, "Trash:"));
+        out.push('\n');
+        for path in trash {
+            out.push_str(&format!("  {}\n", paint(RED, &path.to_string_lossy())));
+        }
+    }
+
+    if !create.is_empty() {
+        out.push_str(&paint(BOLD, "Create:"));
+        out.push('\n');
+        for path in create {
+            out.push_str(&format!("  {}\n", paint(GREEN, &path.to_string_lossy())));
+        }
+    }
+
+    out.push_str(&format!(
+        "{} renamed, {} trashed, {} created\n",
+        changed.len(),
+        trash.len(),
+        create.len()
+    ));
+
+    out
+}
+
+/// Asks whether to proceed. Anything but yes or edit means no.
+pub fn confirm() -> Result<Answer, String> {
+    let stdin = io::stdin();
+    loop {
+        // Like other prompts, on stderr so stdout only has output.
+        eprint!("Proceed? [y/N/e(dit)] ");
+        io::stderr().flush().map_err(|err| err.to_string())?;
+
+        let mut line = String::new();
+        let read = stdin
+            .lock()
+            .read_line(&mut line)
+            .map_err(|err| err.to_string())?;
+        if read == 0 {
+            // End of input.
+            return Ok(Answer::No);
+        }
+
+        match line.trim().to_lowercase().as_str() {
+            "y" | "yes" => return Ok(Answer::Yes),
+            "" | "n" | "no" => return Ok(Answer::No),
+            "e" | "edit" => return Ok(Answer::Edit),
+            _ => continue,
+        }
+    }
+}
+
+/// Splits two strings into their common prefix, the differing parts
+/// and their common suffix.
+fn split_difference<'a>(old: &'a str, new: &'a str) -> (&'a str, &'a str, &'a str, &'a str) {
+    let prefix_len = old
+        .char_indices()
+        .zip(new.chars())
+        .find(|((_, a), b)| a != b)
+        .map_or(old.len().min(new.len()), |((i, _), _)| i);
+    // The prefix must end on a char boundary in new as well.
+    let prefix_len = (0..=prefix_len)
+        .rev()
+        .find(|&i| new.is_char_boundary(i))
+        .unwrap_or(0);
+
+    let (old_rest, new_rest) = (&old[prefix_len..], &new[prefix_len..]);
+    let suffix_len = old_rest
+        .char_indices()
+        .rev()
+        .zip(new_rest.chars().rev())
+        .take_while(|((_, a), b)| a == b)
+        .last()
+        .map_or(0, |((i, _), _)| old_rest.len() - i);
+
+    (
+        &old[..prefix_len],
+        &old_rest[..old_rest.len() - suffix_len],
+        &new_rest[..new_rest.len() - suffix_len],
+        &old_rest[old_rest.len() - suffix_len..],
+    )
+}
+
+fn parent_of(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_difference, summary};
+    use std::path::PathBuf;
+
+    #[test]
+    fn split_paths() {
+        assert_eq!(
+            split_difference("img/photo-01.jpg", "img/holiday-01.jpg"),
+            ("img/", "photo", "holiday", "-01.jpg")
+        );
+        assert_eq!(split_difference("foo", "foo.bak"), ("foo", "", ".bak", ""));
+        assert_eq!(split_difference("aé", "aè"), ("a", "é", "è", ""));
+        assert_eq!(split_difference("abc", "xyz"), ("", "abc", "xyz", ""));
+    }
+
+    #[test]
+    fn plain_summary() {
+        let renames = [
+            ("foo", "bar"),
+            ("same", "same/"),
+            ("a/x", "b/x"),
+            ("a/y", "b/y"),
+            ("a/z", "c/z"),
+        ];
+        let trash = [PathBuf::from("old.log")];
+        let create = [PathBuf::from("new/")];
+
+        assert_eq!(
+            summary(&renames, &trash, &create, false),
+            "Rename:\n  a/z -> c/z\n  foo -> bar\n\
+             Move from a/ to b/:\n  x\n  y\n\
+             Trash:\n  old.log\n\
+             Create:\n  new/\n\
+             4 renamed, 1 trashed, 1 created\n"
+        );
+    }
+}
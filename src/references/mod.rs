@@ -0,0 +1,428 @@
+//! Rewrites textual references to renamed paths.
+//!
+//! After files are moved, imports, markdown links and config
+//! entries which mention the old paths are left dangling. This
+//! module scans text files under a root directory for the old
+//! paths of every rename, either absolute or relative to the
+//! referencing file, and replaces them with the new ones.
+//!
+//! Rewrites are planned before anything is renamed so that a
+//! preview can be shown, and only applied once all renames
+//! succeed. Each rewrite is logged, and if one fails the earlier
+//! ones are restored.
+//!
+//! A relative reference is resolved against the directory of the
+//! file which contains it, like a markdown link or an include. So
+//! `README.md` in `docs/guide.md` means `docs/README.md`, and is
+//! only rewritten when that file moves. The replacement is relative
+//! to where the referencing file ends up.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::filepath::clean;
+use super::mmv::Log;
+
+/// Files larger than this are assumed not to be source/config files.
+const MAX_FILE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Directories which are never searched for references.
+const SKIPPED_DIRS: [&str; 2] = [".git", "target"];
+
+#[derive(Debug)]
+pub struct Rewrite {
+    /// Location of the file once all renames are done.
+    pub path: PathBuf,
+    pub original: String,
+    pub updated: String,
+}
+
+/// Returns a rewrite for every text file under `root` which
+/// mentions the source path of an entry in `files`.
+pub fn plan<P: AsRef<Path>>(root: &Path, files: &HashMap<P, P>) -> io::Result<Vec<Rewrite>> {
+    let cwd = env::current_dir()?;
+    let root = clean(cwd.join(root));
+    let moves = files
+        .iter()
+        .map(|(src, dst)| (clean(cwd.join(src)), clean(cwd.join(dst))))
+        .filter(|(src, dst)| src != dst)
+        .collect::<Vec<(PathBuf, PathBuf)>>();
+
+    let mut rewrites = Vec::<Rewrite>::new();
+    if moves.is_empty() {
+        return Ok(rewrites);
+    }
+
+    let mut text_files = Vec::<PathBuf>::new();
+    collect_text_files(&root, &mut text_files)?;
+    text_files.sort();
+
+    for path in text_files {
+        let original = match read_text(&path)? {
+            Some(contents) => contents,
+            None => continue,
+        };
+
+        let dir = path.parent().unwrap_or(&root);
+        let pairs = replacement_pairs(dir, &moved_location(dir, &moves), &moves);
+        let updated = replace_references(&original, &pairs);
+        if updated != original {
+            rewrites.push(Rewrite {
+                path: moved_location(&path, &moves),
+                original,
+                updated,
+            });
+        }
+    }
+
+    Ok(rewrites)
+}
+
+/// Writes the updated contents of each rewrite to disk. On failure,
+/// the files already rewritten get their original contents back.
+pub fn apply(rewrites: &[Rewrite], log: &mut Log) -> io::Result<()> {
+    for (i, rewrite) in rewrites.iter().enumerate() {
+        if let Err(err) = log.rewrite(&rewrite.path, || fs::write(&rewrite.path, &rewrite.updated))
+        {
+            undo(&rewrites[..i], log);
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Restores the original contents of rewrites, latest first.
+pub fn undo(rewrites: &[Rewrite], log: &mut Log) {
+    for rewrite in rewrites.iter().rev() {
+        log.restore(&rewrite.path, || {
+            fs::write(&rewrite.path, &rewrite.original)
+        })
+        .ok();
+    }
+}
+
+/// Returns a line based diff of all rewrites for the user to review.
+pub fn preview(rewrites: &[Rewrite]) -> String {
+    let mut out = String::new();
+    for rewrite in rewrites {
+        out.push_str(&format!(
+            "--- {}\n+++ {}\n",
+            rewrite.path.display(),
+            rewrite.path.display()
+        ));
+
+        let old_lines = rewrite.original.lines();
+        let new_lines = rewrite.updated.lines();
+        // Replacements never add or remove newlines, so lines
+        // can be compared pairwise.
+        for (n, (old, new)) in old_lines.zip(new_lines).enumerate() {
+            if old != new {
+                out.push_str(&format!("@@ line {} @@\n-{}\n+{}\n", n + 1, old, new));
+            }
+        }
+    }
+
+    out
+}
+
+/// Builds (old, new) string pairs to search for in a file which is
+/// in `dir` before the renames and in `new_dir` after them. Pairs are
+/// sorted longest first so that `a/b/c` is preferred over `a/b` when
+/// both were renamed.
+fn replacement_pairs(
+    dir: &Path,
+    new_dir: &Path,
+    moves: &[(PathBuf, PathBuf)],
+) -> Vec<(String, String)> {
+    let mut pairs = Vec::<(String, String)>::new();
+    for (src, dst) in moves {
+        if let (Some(src_str), Some(dst_str)) = (src.to_str(), dst.to_str()) {
+            pairs.push((src_str.to_string(), dst_str.to_string()));
+        }
+
+        // The directory of the file itself, or one of its parents,
+        // would only be referred to as `..`.
+        if dir.starts_with(src) {
+            continue;
+        }
+
+        let (rel_src, rel_dst) = (relative(dir, src), relative(new_dir, dst));
+        if let (Some(src_str), Some(dst_str)) = (rel_src.to_str(), rel_dst.to_str()) {
+            if !dst_str.is_empty() {
+                pairs.push((src_str.to_string(), dst_str.to_string()));
+            }
+        }
+    }
+
+    pairs.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+    pairs.dedup_by(|a, b| a.0 == b.0);
+    pairs
+}
+
+/// Characters which can make up a file name. A match must not be
+/// surrounded by these, otherwise `foo` would match inside `foobar`.
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+/// Replaces every whole-path occurrence of the old paths in `text`.
+///
+/// A path may be followed by `/` (a file inside a renamed directory)
+/// and preceded by `./`, but not by any other path component.
+fn replace_references(text: &str, pairs: &[(String, String)]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+
+    'outer: while i < text.len() {
+        let rest = &text[i..];
+        if starts_reference(&text[..i]) {
+            for (old, new) in pairs {
+                if !rest.starts_with(old.as_str()) {
+                    continue;
+                }
+
+                let next = rest[old.len()..].chars().next();
+                if next.is_none_or(|c| !is_name_char(c)) {
+                    out.push_str(new);
+                    i += old.len();
+                    continue 'outer;
+                }
+            }
+        }
+
+        // No match at this position, copy a single character.
+        let c = rest.chars().next().unwrap();
+        out.push(c);
+        i += c.len_utf8();
+    }
+
+    out
+}
+
+/// Checks whether a path reference may begin right after `before`.
+fn starts_reference(before: &str) -> bool {
+    let mut chars = before.chars().rev();
+    match chars.next() {
+        None => true,
+        Some('/') => {
+            // Only `./` may precede a relative path, `x/` means
+            // the path belongs to another directory.
+            chars.next() == Some('.') && chars.next().is_none_or(|c| !is_name_char(c) && c != '/')
+        }
+        Some(c) => !is_name_char(c),
+    }
+}
+
+/// Returns `path` relative to the directory `base`, both of which
+/// must be absolute and clean. Eg. `/r/a/b` from `/r/c` is `../a/b`.
+fn relative(base: &Path, path: &Path) -> PathBuf {
+    let common = base
+        .components()
+        .zip(path.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut out = PathBuf::new();
+    for _ in base.components().skip(common) {
+        out.push("..");
+    }
+    for component in path.components().skip(common) {
+        out.push(component);
+    }
+
+    out
+}
+
+/// Returns where `path` ends up after all `moves` are done. The
+/// innermost moved path containing it decides, Eg. `a/b/c` follows
+/// `a/b` rather than `a` when both moved.
+fn moved_location(path: &Path, moves: &[(PathBuf, PathBuf)]) -> PathBuf {
+    moves
+        .iter()
+        .filter_map(|(src, dst)| Some((src, dst, path.strip_prefix(src).ok()?)))
+        .max_by_key(|(src, _, _)| src.components().count())
+        .map_or_else(|| path.to_path_buf(), |(_, dst, rest)| dst.join(rest))
+}
+
+fn collect_text_files(dir: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+
+        // Symbolic links are not followed to avoid loops and
+        // rewriting files outside of root.
+        if file_type.is_dir() {
+            if !SKIPPED_DIRS.iter().any(|name| entry.file_name() == *name) {
+                collect_text_files(&path, out)?;
+            }
+        } else if file_type.is_file() && entry.metadata()?.len() <= MAX_FILE_SIZE {
+            out.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns file contents if it looks like a text file.
+fn read_text(path: &Path) -> io::Result<Option<String>> {
+    let bytes = fs::read(path)?;
+    if bytes.contains(&0) {
+        return Ok(None);
+    }
+
+    Ok(String::from_utf8(bytes).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ioutils::temp_dir;
+    use super::super::mmv::Log;
+    use super::{apply, moved_location, plan, relative, replace_references, Rewrite};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn pairs(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items
+            .iter()
+            .map(|&(old, new)| (old.to_string(), new.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn replace_whole_paths() {
+        let pairs = pairs(&[("docs/old.md", "docs/new.md"), ("foo", "bar")]);
+        let cases = [
+            ("[link](docs/old.md)", "[link](docs/new.md)"),
+            ("see ./docs/old.md", "see ./docs/new.md"),
+            ("include: foo", "include: bar"),
+            ("foo/baz.txt", "bar/baz.txt"),
+            // Partial names and other directories are left alone.
+            ("foobar", "foobar"),
+            ("foo.rs", "foo.rs"),
+            ("x/foo", "x/foo"),
+            ("../foo", "../foo"),
+        ];
+
+        for (text, expected) in cases.iter() {
+            assert_eq!(replace_references(text, &pairs), *expected);
+        }
+    }
+
+    #[test]
+    fn prefer_longest_path() {
+        let pairs = pairs(&[("a/b/c", "x"), ("a/b", "y")]);
+        assert_eq!(replace_references("a/b/c a/b/d", &pairs), "x y/d");
+    }
+
+    #[test]
+    fn innermost_move_wins() {
+        let moves = vec![
+            (PathBuf::from("/r/a"), PathBuf::from("/r/x")),
+            (PathBuf::from("/r/a/b"), PathBuf::from("/r/y")),
+        ];
+        for moves in &[moves.clone(), moves.into_iter().rev().collect()] {
+            assert_eq!(
+                moved_location(&PathBuf::from("/r/a/b/c.md"), moves),
+                PathBuf::from("/r/y/c.md")
+            );
+            assert_eq!(
+                moved_location(&PathBuf::from("/r/a/d.md"), moves),
+                PathBuf::from("/r/x/d.md")
+            );
+        }
+    }
+
+    #[test]
+    fn relative_paths() {
+        let cases = [
+            ("/r", "/r/a.md", "a.md"),
+            ("/r/docs", "/r/a.md", "../a.md"),
+            ("/r/docs", "/r/docs/sub/a.md", "sub/a.md"),
+            ("/r/x/y", "/r/z/a.md", "../../z/a.md"),
+        ];
+
+        for (base, path, expected) in cases.iter() {
+            assert_eq!(
+                relative(Path::new(base), Path::new(path)),
+                Path::new(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_against_referencing_file() {
+        let dir = PathBuf::from(temp_dir("", "mmv-references-").unwrap());
+        fs::create_dir(dir.join("docs")).unwrap();
+        fs::write(dir.join("README.md"), "readme").unwrap();
+        fs::write(dir.join("docs/README.md"), "docs readme").unwrap();
+        fs::write(dir.join("index.md"), "[readme](README.md)").unwrap();
+        fs::write(
+            dir.join("docs/guide.md"),
+            "[docs home](README.md) [top](../README.md)",
+        )
+        .unwrap();
+
+        let mut files = HashMap::new();
+        files.insert(dir.join("README.md"), dir.join("INTRO.md"));
+        let rewrites = plan(&dir, &files).unwrap();
+
+        let updated = rewrites
+            .iter()
+            .map(|r| (r.path.strip_prefix(&dir).unwrap(), r.updated.as_str()))
+            .collect::<Vec<(&Path, &str)>>();
+        assert_eq!(
+            updated,
+            [
+                (
+                    Path::new("docs/guide.md"),
+                    "[docs home](README.md) [top](../INTRO.md)"
+                ),
+                (Path::new("index.md"), "[readme](INTRO.md)"),
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restore_on_failure() {
+        let dir = PathBuf::from(temp_dir("", "mmv-references-").unwrap());
+        fs::write(dir.join("a.md"), "old").unwrap();
+        let rewrite = |path: PathBuf| Rewrite {
+            path,
+            original: String::from("old"),
+            updated: String::from("new"),
+        };
+        // The second file cannot be written.
+        let rewrites = vec![rewrite(dir.join("a.md")), rewrite(dir.join("missing/b.md"))];
+        let mut log = Log::default();
+
+        assert!(apply(&rewrites, &mut log).is_err());
+        assert_eq!(fs::read_to_string(dir.join("a.md")).unwrap(), "old");
+        assert_eq!(log.rewrites.len(), 2);
+        assert!(log.rewrites[0].error.is_none() && log.rewrites[1].error.is_some());
+        assert_eq!(log.rollback.len(), 1);
+        assert_eq!(log.rollback[0].dst, Some(dir.join("a.md")));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn location_after_move() {
+        let moves = vec![(PathBuf::from("/r/dir"), PathBuf::from("/r/other"))];
+        assert_eq!(
+            moved_location(&PathBuf::from("/r/dir/a.md"), &moves),
+            PathBuf::from("/r/other/a.md")
+        );
+        assert_eq!(
+            moved_location(&PathBuf::from("/r/b.md"), &moves),
+            PathBuf::from("/r/b.md")
+        );
+    }
+}
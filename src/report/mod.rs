@@ -0,0 +1,298 @@
+//! Machine-readable report of what a run executed.
+//!
+//! The schema is documented in the README and versioned by the
+//! `version` field, which is bumped whenever a field changes meaning
+//! or is removed. New fields may be added without a version bump.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use super::mmv::{Log, LogEntry};
+
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub version: u32,
+    pub started_at: String,
+    pub finished_at: String,
+    /// "success" or "failed".
+    pub outcome: &'static str,
+    pub error: Option<String>,
+    /// Requested renames, sorted by source.
+    pub mappings: Vec<Mapping>,
+    /// Existing destinations moved aside by a backup conflict policy,
+    /// sorted by source.
+    pub backups: Vec<Mapping>,
+    pub trash: Vec<String>,
+    pub create: Vec<String>,
+    /// Executed edges in order, including temporary hops.
+    pub edges: Vec<Step>,
+    /// Edges undone after a failure, in order.
+    pub rollback: Vec<Step>,
+    /// Files whose references were rewritten, in order.
+    pub rewrites: Vec<Step>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Mapping {
+    pub src: String,
+    pub dst: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Step {
+    pub src: String,
+    /// None when src was removed.
+    pub dst: Option<String>,
+    pub started_at: String,
+    pub finished_at: String,
+    pub outcome: &'static str,
+    pub error: Option<String>,
+}
+
+impl Report {
+    pub fn new(
+        renames: &HashMap<PathBuf, PathBuf>,
+        backups: &[PathBuf],
+        trash: &[PathBuf],
+        create: &[PathBuf],
+        log: &Log,
+        started: SystemTime,
+        result: &Result<(), String>,
+    ) -> Self {
+        let backed_up = backups.iter().collect::<HashSet<&PathBuf>>();
+        let (mut backups, mut mappings) = (Vec::<Mapping>::new(), Vec::<Mapping>::new());
+        for (src, dst) in renames {
+            let mapping = Mapping {
+                src: display(src),
+                dst: display(dst),
+            };
+            if backed_up.contains(src) {
+                backups.push(mapping);
+            } else {
+                mappings.push(mapping);
+            }
+        }
+        mappings.sort_by(|a, b| a.src.cmp(&b.src));
+        backups.sort_by(|a, b| a.src.cmp(&b.src));
+
+        Report {
+            version: VERSION,
+            started_at: timestamp(started),
+            finished_at: timestamp(SystemTime::now()),
+            outcome: outcome(result.is_ok()),
+            error: result.as_ref().err().cloned(),
+            mappings,
+            backups,
+            trash: trash.iter().map(|path| display(path)).collect(),
+            create: create.iter().map(|path| display(path)).collect(),
+            edges: log.edges.iter().map(Step::from).collect(),
+            rollback: log.rollback.iter().map(Step::from).collect(),
+            rewrites: log.rewrites.iter().map(Step::from).collect(),
+        }
+    }
+
+    /// Writes the report as JSON to `path`, or stdout if it is `-`.
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let mut json = serde_json::to_string_pretty(self).map_err(|err| err.to_string())?;
+        json.push('\n');
+
+        let res = if path == Path::new("-") {
+            io::stdout().write_all(json.as_bytes())
+        } else {
+            fs::write(path, json)
+        };
+        res.map_err(|err| format!("Error writing report {}:\n{}", path.display(), err))
+    }
+}
+
+impl From<&LogEntry> for Step {
+    fn from(entry: &LogEntry) -> Self {
+        Step {
+            src: display(&entry.src),
+            dst: entry.dst.as_ref().map(|dst| display(dst)),
+            started_at: timestamp(entry.started),
+            finished_at: timestamp(entry.finished),
+            outcome: outcome(entry.error.is_none()),
+            error: entry.error.clone(),
+        }
+    }
+}
+
+fn outcome(ok: bool) -> &'static str {
+    if ok {
+        "success"
+    } else {
+        "failed"
+    }
+}
+
+fn display(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// Formats time as RFC 3339 in UTC with millisecond precision,
+/// Eg. `2021-03-04T05:06:07.089Z`.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date.
+/// (See http://howardhinnant.github.io/date_algorithms.html)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::super::ioutils::temp_dir;
+    use super::super::mmv::{rename_logged, Log, Options};
+    use super::{timestamp, Mapping, Report};
+
+    #[test]
+    fn format_timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_millis(951_782_400_123)),
+            "2000-02-29T00:00:00.123Z"
+        );
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(1_614_834_367)),
+            "2021-03-04T05:06:07.000Z"
+        );
+    }
+
+    #[test]
+    fn report_cycle() {
+        let dir = PathBuf::from(temp_dir("", "mmv-report-").unwrap());
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("b"), "b").unwrap();
+
+        let renames = [
+            (dir.join("a"), dir.join("b")),
+            (dir.join("b"), dir.join("a")),
+        ]
+        .iter()
+        .cloned()
+        .collect::<HashMap<PathBuf, PathBuf>>();
+        let mut log = Log::default();
+        let started = SystemTime::now();
+        let result = rename_logged(&renames, None, &Options::default(), &mut log);
+        let report = Report::new(&renames, &[], &[], &[], &log, started, &result);
+
+        assert_eq!(report.outcome, "success");
+        assert_eq!(
+            report.mappings[0],
+            Mapping {
+                src: dir.join("a").to_string_lossy().into_owned(),
+                dst: dir.join("b").to_string_lossy().into_owned(),
+            }
+        );
+        // Swapping two files takes a temporary hop.
+        assert_eq!(report.edges.len(), 3);
+        assert!(report.edges.iter().all(|step| step.outcome == "success"));
+        assert!(report.rollback.is_empty());
+
+        let path = dir.join("report.json");
+        report.write(&path).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["edges"].as_array().unwrap().len(), 3);
+        assert!(json["error"].is_null());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn report_backups() {
+        let renames = [
+            (PathBuf::from("a"), PathBuf::from("b")),
+            (PathBuf::from("b"), PathBuf::from("b~")),
+        ]
+        .iter()
+        .cloned()
+        .collect::<HashMap<PathBuf, PathBuf>>();
+        let backups = [PathBuf::from("b")];
+        let report = Report::new(
+            &renames,
+            &backups,
+            &[],
+            &[],
+            &Log::default(),
+            SystemTime::now(),
+            &Ok(()),
+        );
+
+        let mapping = |src: &str, dst: &str| Mapping {
+            src: src.to_string(),
+            dst: dst.to_string(),
+        };
+        assert_eq!(report.mappings, vec![mapping("a", "b")]);
+        assert_eq!(report.backups, vec![mapping("b", "b~")]);
+    }
+
+    #[test]
+    fn report_rollback() {
+        let dir = PathBuf::from(temp_dir("", "mmv-report-").unwrap());
+        fs::write(dir.join("a"), "a").unwrap();
+
+        // The missing source fails, so every edge executed before it
+        // is undone.
+        let renames = [
+            (dir.join("a"), dir.join("b")),
+            (dir.join("missing"), dir.join("z")),
+        ]
+        .iter()
+        .cloned()
+        .collect::<HashMap<PathBuf, PathBuf>>();
+        let mut log = Log::default();
+        let result = rename_logged(&renames, None, &Options::default(), &mut log);
+        let report = Report::new(&renames, &[], &[], &[], &log, SystemTime::now(), &result);
+
+        assert_eq!(report.outcome, "failed");
+        assert!(report.error.is_some());
+        let failed = report.edges.last().unwrap();
+        assert_eq!(failed.outcome, "failed");
+        assert_eq!(report.rollback.len(), report.edges.len() - 1);
+        assert_eq!(fs::read_to_string(dir.join("a")).unwrap(), "a");
+        assert!(!dir.join("b").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+//! Portable POSIX shell scripts equivalent to a rename, for hosts
+//! where mmv is not installed.
+//!
+//! Commands follow the exact edge order of `rename()`, temporary hops
+//! included. Every move is guarded so that the script fails instead
+//! of overwriting a destination which exists. The undo script runs the
+//! same edges backwards.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use super::mmv::Edge;
+
+static HEADER: &str = "#!/bin/sh\n# Generated by mmv.\nset -e\n\n";
+
+/// Returns a script executing edges, then creating the directories
+/// in `create`.
+pub fn rename_script(edges: &[Edge], create: &[PathBuf]) -> Vec<u8> {
+    let mut out = HEADER.as_bytes().to_vec();
+    let mut made = HashSet::<PathBuf>::new();
+    for edge in edges {
+        push_move(&mut out, &edge.src, &edge.dst, &mut made);
+    }
+    for dir in create {
+        push_command(&mut out, "mkdir -p --", &[dir]);
+    }
+
+    out
+}
+
+/// Returns a script which undoes `rename_script()`. Directories
+/// created along the way are left in place.
+pub fn undo_script(edges: &[Edge]) -> Vec<u8> {
+    let mut out = HEADER.as_bytes().to_vec();
+    let mut made = HashSet::<PathBuf>::new();
+    for edge in edges.iter().rev() {
+        push_move(&mut out, &edge.dst, &edge.src, &mut made);
+    }
+
+    out
+}
+
+/// Writes a script to `path`, or stdout if it is `-`.
+pub fn write(path: &Path, script: &[u8]) -> Result<(), String> {
+    let res = if path == Path::new("-") {
+        io::stdout().write_all(script)
+    } else {
+        fs::write(path, script)
+    };
+    res.map_err(|err| format!("Error writing script {}:\n{}", path.display(), err))
+}
+
+fn push_move(out: &mut Vec<u8>, src: &Path, dst: &Path, made: &mut HashSet<PathBuf>) {
+    // Like rename(), create the destination directory when needed.
+    if let Some(parent) = dst.parent() {
+        if !parent.as_os_str().is_empty()
+            && Some(parent) != src.parent()
+            && made.insert(parent.to_path_buf())
+        {
+            push_command(out, "mkdir -p --", &[parent]);
+        }
+    }
+    push_guard(out, dst);
+    push_command(out, "mv --", &[src, dst]);
+}
+
+/// Makes the script fail if dst exists, dangling symbolic links
+/// included. `mv -n` is not enough, since some implementations skip
+/// an existing destination and still succeed.
+fn push_guard(out: &mut Vec<u8>, dst: &Path) {
+    let dst = quote(dst);
+    out.extend_from_slice(b"[ ! -e ");
+    out.extend_from_slice(&dst);
+    out.extend_from_slice(b" ] && [ ! -L ");
+    out.extend_from_slice(&dst);
+    out.extend_from_slice(b" ] || { printf 'exists: %s\\n' ");
+    out.extend_from_slice(&dst);
+    out.extend_from_slice(b" >&2; exit 1; }\n");
+}
+
+fn push_command(out: &mut Vec<u8>, command: &str, paths: &[&Path]) {
+    out.extend_from_slice(command.as_bytes());
+    for path in paths {
+        out.push(b' ');
+        out.extend_from_slice(&quote(path));
+    }
+    out.push(b'\n');
+}
+
+/// Quotes path for the shell. Inside single quotes every byte is
+/// literal except `'` itself, which is written as `'\''`.
+fn quote(path: &Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &byte in path.as_os_str().as_bytes() {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    use super::super::ioutils::temp_dir;
+    use super::super::mmv::{edges, Edge, Hop};
+    use super::{quote, rename_script, undo_script};
+
+    #[test]
+    fn quote_paths() {
+        assert_eq!(quote(Path::new("foo bar")), b"'foo bar'".to_vec());
+        assert_eq!(quote(Path::new("it's")), b"'it'\\''s'".to_vec());
+        assert_eq!(quote(Path::new("$(rm -rf)`x`")), b"'$(rm -rf)`x`'".to_vec());
+    }
+
+    #[test]
+    fn script_commands() {
+        let edges = [
+            Edge {
+                src: PathBuf::from("a"),
+                dst: PathBuf::from("x/b"),
+                hop: Hop::Direct,
+            },
+            Edge {
+                src: PathBuf::from("c"),
+                dst: PathBuf::from("x/d"),
+                hop: Hop::Direct,
+            },
+        ];
+
+        assert_eq!(
+            String::from_utf8(rename_script(&edges, &[PathBuf::from("new")])).unwrap(),
+            "#!/bin/sh\n# Generated by mmv.\nset -e\n\n\
+             mkdir -p -- 'x'\n\
+             [ ! -e 'x/b' ] && [ ! -L 'x/b' ] || { printf 'exists: %s\\n' 'x/b' >&2; exit 1; }\n\
+             mv -- 'a' 'x/b'\n\
+             [ ! -e 'x/d' ] && [ ! -L 'x/d' ] || { printf 'exists: %s\\n' 'x/d' >&2; exit 1; }\n\
+             mv -- 'c' 'x/d'\n\
+             mkdir -p -- 'new'\n"
+        );
+        assert_eq!(
+            String::from_utf8(undo_script(&edges)).unwrap(),
+            "#!/bin/sh\n# Generated by mmv.\nset -e\n\n\
+             [ ! -e 'c' ] && [ ! -L 'c' ] || { printf 'exists: %s\\n' 'c' >&2; exit 1; }\n\
+             mv -- 'x/d' 'c'\n\
+             [ ! -e 'a' ] && [ ! -L 'a' ] || { printf 'exists: %s\\n' 'a' >&2; exit 1; }\n\
+             mv -- 'x/b' 'a'\n"
+        );
+    }
+
+    #[test]
+    fn run_scripts() {
+        let dir = PathBuf::from(temp_dir("", "mmv-script-").unwrap());
+        let names = ["it's", "-n", "a b", "$HOME"];
+        for name in &names {
+            fs::write(dir.join(name), name).unwrap();
+        }
+
+        // Rotate the files, which needs a temporary hop.
+        let files = (0..names.len())
+            .map(|i| (dir.join(names[i]), dir.join(names[(i + 1) % names.len()])))
+            .collect::<HashMap<PathBuf, PathBuf>>();
+        let edges = edges(&files).unwrap();
+        assert_eq!(edges.len(), names.len() + 1);
+
+        let run = |script: Vec<u8>| {
+            let path = dir.join("script.sh");
+            fs::write(&path, script).unwrap();
+            let status = Command::new("sh").arg(&path).status().unwrap();
+            fs::remove_file(path).unwrap();
+            assert!(status.success());
+        };
+
+        run(rename_script(&edges, &[]));
+        for i in 0..names.len() {
+            let moved = dir.join(names[(i + 1) % names.len()]);
+            assert_eq!(fs::read_to_string(moved).unwrap(), names[i]);
+        }
+
+        run(undo_script(&edges));
+        for name in &names {
+            assert_eq!(fs::read_to_string(dir.join(name)).unwrap(), *name);
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), names.len());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_existing_destination() {
+        let dir = PathBuf::from(temp_dir("", "mmv-script-").unwrap());
+        fs::write(dir.join("a"), "a").unwrap();
+        fs::write(dir.join("b"), "b").unwrap();
+        // Dangling, so only the symbolic link check sees it.
+        std::os::unix::fs::symlink(dir.join("missing"), dir.join("c")).unwrap();
+
+        for dst in &["b", "c"] {
+            let edges = [Edge {
+                src: dir.join("a"),
+                dst: dir.join(dst),
+                hop: Hop::Direct,
+            }];
+            let path = dir.join("script.sh");
+            fs::write(&path, rename_script(&edges, &[])).unwrap();
+            let output = Command::new("sh").arg(&path).output().unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert!(!output.status.success());
+            assert_eq!(
+                String::from_utf8(output.stderr).unwrap(),
+                format!("exists: {}\n", dir.join(dst).display())
+            );
+            assert_eq!(fs::read_to_string(dir.join("a")).unwrap(), "a");
+        }
+        assert_eq!(fs::read_to_string(dir.join("b")).unwrap(), "b");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
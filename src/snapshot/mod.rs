@@ -0,0 +1,129 @@
+//! Detects changes made to paths by other processes.
+//!
+//! The editor can stay open for minutes while files are created,
+//! deleted or replaced. A snapshot records the (dev, inode, mtime,
+//! size) of each path, or that it did not exist, so that the state can
+//! be verified again just before anything is moved.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::filepath::clean;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub dev: u64,
+    pub ino: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub size: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Snapshot {
+    // None when the path did not exist.
+    entries: BTreeMap<PathBuf, Option<Fingerprint>>,
+}
+
+impl Snapshot {
+    pub fn new() -> Self {
+        Snapshot::default()
+    }
+
+    /// Records the current state of `path`. Paths which are already
+    /// recorded keep their first state.
+    pub fn add<P: AsRef<Path>>(&mut self, path: P) {
+        let path = clean(path);
+        let state = fingerprint(&path);
+        self.entries.entry(path).or_insert(state);
+    }
+
+    /// Returns every recorded path.
+    pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.entries.keys()
+    }
+
+    /// Returns a description of every path whose state differs from
+    /// the recorded one.
+    pub fn changes(&self) -> Vec<String> {
+        let mut changes = Vec::<String>::new();
+        for (path, before) in &self.entries {
+            let after = fingerprint(path);
+            let change = match (before, &after) {
+                (None, None) => continue,
+                (Some(before), Some(after)) if before == after => continue,
+                (None, Some(_)) => "created",
+                (Some(_), None) => "deleted",
+                (Some(before), Some(after)) => {
+                    if before.dev != after.dev || before.ino != after.ino {
+                        "replaced"
+                    } else {
+                        "modified"
+                    }
+                }
+            };
+            changes.push(format!("{} was {}", path.display(), change));
+        }
+
+        changes
+    }
+}
+
+/// Returns the fingerprint of `path` itself (symbolic links are not
+/// followed), or None if it does not exist.
+pub fn fingerprint<P: AsRef<Path>>(path: P) -> Option<Fingerprint> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    Some(Fingerprint {
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+        mtime: metadata.mtime(),
+        mtime_nsec: metadata.mtime_nsec(),
+        size: metadata.size(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::super::ioutils::temp_dir;
+    use super::Snapshot;
+
+    #[test]
+    fn detect_changes() {
+        let dir = PathBuf::from(temp_dir("", "mmv-snapshot-").unwrap());
+        for file in &["foo", "bar", "baz", "same"] {
+            fs::write(dir.join(file), "0").unwrap();
+        }
+
+        let mut snapshot = Snapshot::new();
+        for file in &["foo", "bar", "baz", "qux", "same"] {
+            snapshot.add(dir.join(file));
+        }
+        assert!(snapshot.changes().is_empty());
+
+        fs::write(dir.join("foo"), "changed").unwrap();
+        fs::remove_file(dir.join("bar")).unwrap();
+        fs::rename(dir.join("same"), dir.join("baz")).unwrap();
+        fs::write(dir.join("qux"), "0").unwrap();
+
+        assert_eq!(
+            snapshot.changes(),
+            vec![
+                format!("{} was deleted", dir.join("bar").display()),
+                format!("{} was replaced", dir.join("baz").display()),
+                format!("{} was modified", dir.join("foo").display()),
+                format!("{} was created", dir.join("qux").display()),
+                format!("{} was deleted", dir.join("same").display()),
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
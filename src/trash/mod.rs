@@ -0,0 +1,294 @@
+//! Moves files to the trash as described by the freedesktop.org
+//! Trash specification.
+//!
+//! For every trashed file, an info file is first created atomically
+//! in `$XDG_DATA_HOME/Trash/info` (falling back to
+//! `~/.local/share/Trash/info`) which records the original location
+//! and deletion date, then the file itself is moved into
+//! `Trash/files` under the same name.
+//!
+//! Files cannot be renamed across devices, so a file on another
+//! mount than the home trash goes to a trash at the top of that
+//! mount instead: `$topdir/.Trash/$uid` if the administrator created
+//! a sticky `$topdir/.Trash`, `$topdir/.Trash-$uid` otherwise. The
+//! info files of those trashes record paths relative to `$topdir`.
+//!
+//! See https://specifications.freedesktop.org/trash-spec/trashspec-latest.html.
+
+use std::env;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Error, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use super::filepath::clean;
+
+static INFO_EXT: &str = ".trashinfo";
+
+/// A file which was moved to the trash.
+#[derive(Debug)]
+pub struct Trashed {
+    pub original: PathBuf,
+    pub file: PathBuf,
+    pub info: PathBuf,
+}
+
+/// Returns the home trash directory.
+pub fn trash_dir() -> io::Result<PathBuf> {
+    if let Some(data_home) = env::var_os("XDG_DATA_HOME") {
+        if !data_home.is_empty() {
+            return Ok(PathBuf::from(data_home).join("Trash"));
+        }
+    }
+
+    match env::var_os("HOME") {
+        Some(home) if !home.is_empty() => Ok(PathBuf::from(home).join(".local/share/Trash")),
+        _ => Err(Error::new(ErrorKind::NotFound, "Cannot find home trash")),
+    }
+}
+
+/// Moves `path` to the home trash, or to the trash of its mount
+/// when that is another device.
+pub fn trash(path: &Path) -> io::Result<Trashed> {
+    let home_trash = trash_dir()?;
+    let original = clean(env::current_dir()?.join(path));
+    let parent = original.parent().unwrap_or(&original);
+    let dev = fs::metadata(parent)?.dev();
+    if device(&home_trash)? == dev {
+        return trash_to(&home_trash, None, path);
+    }
+
+    let top = mount_top(parent, dev);
+    trash_to(&top_trash_dir(&top), Some(&top), path)
+}
+
+/// Moves a trashed file back to its original location.
+pub fn restore(trashed: &Trashed) -> io::Result<()> {
+    fs::rename(&trashed.file, &trashed.original)?;
+    fs::remove_file(&trashed.info)
+}
+
+/// Returns the device of `path`, or of its closest existing ancestor
+/// since the home trash may not have been created yet.
+fn device(path: &Path) -> io::Result<u64> {
+    let mut last_err = Error::from(ErrorKind::NotFound);
+    for dir in path.ancestors() {
+        match fs::metadata(dir) {
+            Ok(metadata) => return Ok(metadata.dev()),
+            Err(err) => last_err = err,
+        }
+    }
+
+    Err(last_err)
+}
+
+/// Returns the topmost ancestor of `dir` which is on device `dev`,
+/// Eg. the mount point of its filesystem.
+fn mount_top(dir: &Path, dev: u64) -> PathBuf {
+    let mut top = dir;
+    for parent in dir.ancestors().skip(1) {
+        match fs::metadata(parent) {
+            Ok(metadata) if metadata.dev() == dev => top = parent,
+            _ => break,
+        }
+    }
+
+    top.to_path_buf()
+}
+
+/// Returns the trash directory of the user at the top of a mount.
+fn top_trash_dir(top: &Path) -> PathBuf {
+    // SAFETY: getuid() cannot fail and has no side effects.
+    let uid = unsafe { libc::getuid() };
+
+    // A shared `.Trash` must be a sticky directory, not a symbolic
+    // link, or users could remove or redirect each other's files.
+    let shared = top.join(".Trash");
+    if let Ok(metadata) = fs::symlink_metadata(&shared) {
+        if metadata.is_dir() && metadata.mode() & 0o1000 != 0 {
+            let dir = shared.join(uid.to_string());
+            match DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(_) => return dir,
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => return dir,
+                Err(_) => {}
+            }
+        }
+    }
+
+    top.join(format!(".Trash-{}", uid))
+}
+
+/// Moves `path` into `trash_dir`. Trashes at the top of a mount
+/// record the original location relative to that mount.
+fn trash_to(trash_dir: &Path, top: Option<&Path>, path: &Path) -> io::Result<Trashed> {
+    // Fail before creating any info file.
+    fs::symlink_metadata(path)?;
+
+    let original = clean(env::current_dir()?.join(path));
+    let name = match original.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.to_string(),
+        None => return Err(Error::new(ErrorKind::InvalidInput, "Invalid file name")),
+    };
+
+    let files_dir = trash_dir.join("files");
+    let info_dir = trash_dir.join("info");
+    for dir in &[&files_dir, &info_dir] {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+
+    // The info file reserves the name, so it must be created with
+    // O_EXCL before the file is moved.
+    for i in 1..10000 {
+        let trashed_name = if i == 1 {
+            name.clone()
+        } else {
+            format!("{}.{}", name, i)
+        };
+        let info = info_dir.join(format!("{}{}", trashed_name, INFO_EXT));
+        let mut info_file = match OpenOptions::new().write(true).create_new(true).open(&info) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        };
+
+        let file = files_dir.join(&trashed_name);
+        let result = write!(
+            info_file,
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            encode_path(top.map_or(&*original, |top| {
+                original.strip_prefix(top).unwrap_or(&original)
+            })),
+            deletion_date()
+        )
+        .and_then(|_| {
+            // A file might exist without an info file after a crash.
+            if fs::symlink_metadata(&file).is_ok() {
+                return Err(Error::from(ErrorKind::AlreadyExists));
+            }
+            fs::rename(&original, &file)
+        });
+
+        match result {
+            Ok(_) => {
+                return Ok(Trashed {
+                    original,
+                    file,
+                    info,
+                })
+            }
+            Err(err) => {
+                fs::remove_file(&info).ok();
+                if err.kind() == ErrorKind::AlreadyExists {
+                    continue;
+                }
+                return Err(err);
+            }
+        }
+    }
+
+    Err(Error::other("Failed to find a free name in trash"))
+}
+
+/// Percent-encodes a path as required for the `Path` key.
+fn encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+/// Returns the current local time as `YYYY-MM-DDThh:mm:ss`.
+fn deletion_date() -> String {
+    // SAFETY: localtime_r() only writes into the provided struct.
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    use super::super::ioutils::temp_dir;
+    use super::{encode_path, restore, top_trash_dir, trash_to};
+
+    #[test]
+    fn encode_trash_path() {
+        assert_eq!(encode_path(Path::new("/a b/ü.txt")), "/a%20b/%C3%BC.txt");
+    }
+
+    #[test]
+    fn trash_and_restore() {
+        let dir = PathBuf::from(temp_dir("", "mmv-trash-").unwrap());
+        let trash_dir = dir.join("Trash");
+        let foo = dir.join("foo");
+
+        // Trash two files with the same name.
+        fs::write(&foo, "0").unwrap();
+        let first = trash_to(&trash_dir, None, &foo).unwrap();
+        fs::write(&foo, "1").unwrap();
+        let second = trash_to(&trash_dir, None, &foo).unwrap();
+
+        assert!(fs::metadata(&foo).is_err());
+        assert_eq!(first.file, trash_dir.join("files/foo"));
+        assert_eq!(second.file, trash_dir.join("files/foo.2"));
+        assert_eq!(fs::read_to_string(&second.file).unwrap(), "1");
+        let info = fs::read_to_string(&first.info).unwrap();
+        assert!(info.starts_with(&format!("[Trash Info]\nPath={}\n", foo.display())));
+
+        restore(&second).unwrap();
+        assert_eq!(fs::read_to_string(&foo).unwrap(), "1");
+        assert!(fs::metadata(&second.info).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn trash_on_other_mount() {
+        let dir = PathBuf::from(temp_dir("", "mmv-trash-").unwrap());
+        // SAFETY: getuid() cannot fail and has no side effects.
+        let uid = unsafe { libc::getuid() };
+        let foo = dir.join("sub/foo");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(&foo, "0").unwrap();
+
+        let trash_dir = top_trash_dir(&dir);
+        assert_eq!(trash_dir, dir.join(format!(".Trash-{}", uid)));
+        let trashed = trash_to(&trash_dir, Some(&dir), &foo).unwrap();
+        let info = fs::read_to_string(&trashed.info).unwrap();
+        assert!(info.starts_with("[Trash Info]\nPath=sub/foo\n"));
+        restore(&trashed).unwrap();
+        assert_eq!(fs::read_to_string(&foo).unwrap(), "0");
+
+        // A sticky shared trash is preferred, a plain one is not.
+        fs::create_dir(dir.join(".Trash")).unwrap();
+        assert_eq!(top_trash_dir(&dir), trash_dir);
+        fs::set_permissions(dir.join(".Trash"), fs::Permissions::from_mode(0o1777)).unwrap();
+        assert_eq!(top_trash_dir(&dir), dir.join(format!(".Trash/{}", uid)));
+        assert!(fs::metadata(dir.join(format!(".Trash/{}", uid)))
+            .unwrap()
+            .is_dir());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}